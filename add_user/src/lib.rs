@@ -14,7 +14,16 @@ use std::env;
 use std::fmt::{Display, Formatter};
 use std::time::{Duration, SystemTime};
 use aws_sdk_dynamodb::model::AttributeAction::Add;
+use aws_sdk_dynamodb::error::{TransactWriteItemsErrorKind, TransactionCanceledException};
+use aws_sdk_dynamodb::model::{AttributeValue, Put, TransactWriteItem};
 use jsonwebtoken::crypto::sign;
+use selektor_common::entitlement::EntitlementStatus;
+use selektor_common::push_scheduler::{self, PushSchedule, PushScheduler, Timing};
+use selektor_common::schedule::{is_legacy_bucket, queue_key, version_key, Jitter, Scheduler, Tick, QUEUE_RETENTION_SECONDS};
+use selektor_common::schedule_item::{self, Item};
+use selektor_common::dynamodb::check_tables;
+use selektor_common::schema;
+use std::str::FromStr;
 
 pub const XCODE_DEV_KEY: &str = "-----BEGIN PUBLIC KEY-----
 MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE4o5o/BwfrYZQu8bgyjF8/YtSyIRO
@@ -26,10 +35,16 @@ const PARTITION: &str = "PARTITION";
 const ENTITLEMENTS_TABLE_NAME: &str = "ENTITLEMENTS_TABLE_NAME";
 const DYNAMODB_ENDPOINT: &str = "DYNAMODB_ENDPOINT";
 const SIGNING_KEY_ID: &str = "SIGNING_KEY_ID";
+const SCHEDULE_TABLE_NAME: &str = "SCHEDULE_TABLE_NAME";
+const ARCHIVE_TABLE_NAME: &str = "ARCHIVE_TABLE_NAME";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddUserRequest {
-    transaction_jws: String
+    transaction_jws: String,
+    /// The subscription's signed renewal info, which says whether the App
+    /// Store is still trying to renew it.
+    #[serde(default)]
+    renewal_info_jws: Option<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -67,7 +82,20 @@ pub async fn add_user(request: AddUserRequest) -> Result<AddUserResponse, Error>
 
     let verify_key = STANDARD.decode(verify_key_raw)?;
     println!("verifying transaction info...");
-    let user_info = verify_transaction(request.transaction_jws, verify_key)?;
+    let user_info = verify_transaction(request.transaction_jws, verify_key.to_owned())?;
+    let renewal_info = match request.renewal_info_jws {
+        Some(renewal_info_jws) => Some(verify_renewal_info(renewal_info_jws, verify_key)?),
+        None => None
+    };
+    if let Some(renewal_info) = &renewal_info {
+        if renewal_info.original_transaction_id != user_info.original_transaction_id {
+            return Err(Error::from(AddUserError {
+                reason: format!("renewal info is for transaction {}, not {}",
+                                renewal_info.original_transaction_id, user_info.original_transaction_id)
+            }))
+        }
+    }
+    let user_info = user_info.renewing(renewal_info.as_ref(), SystemTime::now());
 
     println!("verified info: {:#?}", user_info);
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
@@ -119,23 +147,245 @@ pub async fn add_user(request: AddUserRequest) -> Result<AddUserResponse, Error>
     ddb_client.put_item()
         .set_table_name(Some(table_name))
         .set_item(Some(HashMap::from([
-            (String::from("part"), ddb::model::AttributeValue::S(partition)),
-            (String::from("id"), ddb::model::AttributeValue::S(user_info.id)),
+            (String::from("part"), ddb::model::AttributeValue::S(partition.to_owned())),
+            (String::from("id"), ddb::model::AttributeValue::S(user_info.id.to_owned())),
             (String::from("ends"), ddb::model::AttributeValue::N(
                 user_info.end_date.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis().to_string()
-            ))
+            )),
+            (String::from("status"), ddb::model::AttributeValue::S(user_info.status.to_string()))
         ])))
         .send()
         .await?;
     println!("put item into dynamodb");
+    if user_info.status == EntitlementStatus::Active {
+        let push_scheduler = push_scheduler::for_scheduler(&Scheduler::from_env()?, &config)?;
+        let restored = restore_schedules(&ddb_client, &partition, &user_info.id, push_scheduler.as_deref()).await?;
+        if restored > 0 {
+            println!("restored {} archived schedules", restored);
+        }
+    }
     Ok(AddUserResponse{token: token})
 }
 
+fn number(item: &HashMap<String, AttributeValue>, name: &str) -> Option<u64> {
+    match item.get(name) {
+        Some(AttributeValue::N(n)) => u64::from_str(n).ok(),
+        _ => None
+    }
+}
+
+/// Brings an archived schedule that was due at `next_fire` up to `now`, as
+/// its catch-up policy would have had it kept firing. Occurrences the policy
+/// sends go out next tick, held from the first one missed the way quiet hours
+/// hold them, and those it drops count as missed. Returns the schedule's
+/// `next_fire` now, and when its EventBridge schedule fires, unless it never
+/// fires again.
+fn catch_up(schedule: &mut Item, next_fire: u64, offset: u64, now: u64, tick: Tick) -> Option<(u64, Timing)> {
+    if next_fire > now {
+        return Some((next_fire, schedule_item::push_timing(schedule, next_fire)))
+    }
+    let recurrence = match schedule_item::recurrence(schedule, offset) {
+        Ok(Some(recurrence)) => recurrence,
+        // It can't fire, so it can't fall behind either.
+        _ => return Some((next_fire, schedule_item::push_timing(schedule, next_fire)))
+    };
+    let policy = schedule_item::catch_up(schedule).unwrap_or_default();
+    let held = number(schedule, "deferred_from");
+    let catch_up = match held {
+        Some(held) => policy.apply_held(held, &recurrence, now, tick),
+        None => policy.apply(next_fire, &recurrence, now, tick)
+    };
+    let due = if catch_up.fires > 0 {
+        schedule.insert(String::from("deferred_from"), AttributeValue::N(held.unwrap_or(next_fire).to_string()));
+        Some(tick.next(now))
+    } else {
+        schedule.remove("deferred_from");
+        let missed = number(schedule, "missed_fires").unwrap_or(0) + catch_up.missed;
+        schedule.insert(String::from("missed_fires"), AttributeValue::N(missed.to_string()));
+        catch_up.next_fire
+    };
+    match due {
+        Some(due) => schedule.insert(String::from("next_fire"), AttributeValue::N(due.to_string())),
+        None => schedule.remove("next_fire")
+    };
+    // A rate keeps to the schedule's occurrences, and a push owed before the
+    // first of them is sent by the tick.
+    due.map(|due| match schedule_item::push_timing(schedule, due) {
+        Timing::Rate { interval, .. } => (due, Timing::new(catch_up.next_fire.unwrap_or(due), Some(interval))),
+        timing => (due, timing)
+    })
+}
+
+/// Whether restoring a schedule was canceled because it's in the schedules
+/// table again.
+fn uploaded_again(canceled: &TransactionCanceledException) -> bool {
+    canceled.cancellation_reasons().unwrap_or_default().iter()
+        .any(|reason| reason.code() == Some("ConditionalCheckFailed"))
+}
+
+/// Moves the entitlement's schedules that `purge_expired` archived back into
+/// the schedules table, so someone who resubscribes before they expire gets
+/// them back, caught up to now. A schedule that's been uploaded again since
+/// stays as it is, and its archived copy is dropped. Returns how many were
+/// restored.
+///
+/// With queued schedules, a restored schedule's queue entry is written along
+/// with it. With `push_scheduler`, each restored schedule gets a push
+/// schedule again, since the old one was removed when it fired for a row
+/// that was gone.
+async fn restore_schedules(ddb_client: &ddb::Client, partition: &str, entitlement: &str, push_scheduler: Option<&dyn PushScheduler>) -> Result<usize, Error> {
+    let schedule_table_name = env::var(SCHEDULE_TABLE_NAME)?;
+    let archive_table_name = env::var(ARCHIVE_TABLE_NAME)?;
+    let scheduler = Scheduler::from_env()?;
+    let tick = Tick::from_env()?;
+    let jitter = Jitter::from_env()?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let mut restored = 0;
+    let mut start_key = None;
+    loop {
+        let output = ddb_client.query()
+            .table_name(archive_table_name.to_owned())
+            .index_name(schema::ENTITLEMENT_INDEX.name)
+            .key_condition_expression("#ent = :ent AND #part = :part")
+            .expression_attribute_names("#part", "part")
+            .expression_attribute_names("#ent", "entitlement")
+            .expression_attribute_values(":part", AttributeValue::S(partition.to_owned()))
+            .expression_attribute_values(":ent", AttributeValue::S(entitlement.to_owned()))
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        for item in output.items().unwrap_or_default() {
+            let id = match item.get("id") {
+                Some(AttributeValue::S(id)) => id.to_owned(),
+                _ => continue
+            };
+            // TTL deletes can lag by days; an expired schedule stays gone.
+            if number(item, "expires").is_some_and(|expires| expires < now) {
+                continue
+            }
+            let mut schedule = item.clone();
+            schedule.remove("expires");
+            // Its occurrences since it was archived are long gone, and
+            // EventBridge won't schedule a push for one.
+            let due = match number(item, "next_fire") {
+                Some(next_fire) if !is_legacy_bucket(next_fire) => catch_up(&mut schedule, next_fire, jitter.offset(&id), now, tick),
+                _ => None
+            };
+            let mut transaction = ddb_client.transact_write_items()
+                .transact_items(TransactWriteItem::builder()
+                    .put(Put::builder()
+                        .table_name(schedule_table_name.to_owned())
+                        .set_item(Some(schedule))
+                        .condition_expression("attribute_not_exists(#id)")
+                        .expression_attribute_names("#id", "id")
+                        .build())
+                    .build());
+            if let (Some(queue_table), Some(AttributeValue::S(shard)), Some((next_fire, _))) = (scheduler.queue_table(), item.get("shard"), due) {
+                let bucket = tick.queue_bucket(next_fire, now);
+                transaction = transaction.transact_items(TransactWriteItem::builder()
+                    .put(Put::builder()
+                        .table_name(queue_table)
+                        .item("bucket", AttributeValue::S(queue_key(shard, bucket)))
+                        .item("id", AttributeValue::S(id.to_owned()))
+                        .item("next_fire", AttributeValue::N(next_fire.to_string()))
+                        .item("expires", AttributeValue::N((bucket + QUEUE_RETENTION_SECONDS).to_string()))
+                        .build())
+                    .build());
+            }
+            match transaction.send().await {
+                Ok(_) => {
+                    // Only now, so that a copy uploaded again keeps its own.
+                    if let (Some(push_scheduler), Some((_, timing))) = (push_scheduler, due) {
+                        push_scheduler.put(&PushSchedule::new(partition, &id, timing)).await?;
+                    }
+                    restored += 1
+                },
+                Err(e) => {
+                    let e = e.into_service_error();
+                    match &e.kind {
+                        TransactWriteItemsErrorKind::TransactionCanceledException(canceled) if uploaded_again(canceled) => {
+                            println!("{} was uploaded again, not restoring it", id);
+                        },
+                        _ => return Err(Error::from(e))
+                    }
+                }
+            }
+            ddb_client.delete_item()
+                .table_name(archive_table_name.to_owned())
+                .key("part", AttributeValue::S(partition.to_owned()))
+                .key("id", AttributeValue::S(id))
+                .send()
+                .await?;
+        }
+        match output.last_evaluated_key() {
+            Some(key) => start_key = Some(key.clone()),
+            None => break
+        }
+    }
+    if restored > 0 {
+        // Their schedules changed, so an update made against the old
+        // version mustn't go through.
+        ddb_client.update_item()
+            .table_name(schedule_table_name)
+            .key("part", AttributeValue::S(partition.to_owned()))
+            .key("id", AttributeValue::S(version_key(entitlement)))
+            .update_expression("ADD #version :one")
+            .expression_attribute_names("#version", "schedule_version")
+            .expression_attribute_values(":one", AttributeValue::N(String::from("1")))
+            .send()
+            .await?;
+    }
+    Ok(restored)
+}
+
 #[derive(Debug)]
 pub struct UserInfo {
     pub id: String,
+    pub original_transaction_id: String,
     pub start_date: SystemTime,
-    pub end_date: SystemTime
+    pub end_date: SystemTime,
+    pub status: EntitlementStatus
+}
+
+impl UserInfo {
+    /// Makes an expired entitlement `grace` or `billing_retry` while the App
+    /// Store is still trying to renew it, as its signed `renewal` info says.
+    /// The entitlement lasts until the grace period ends.
+    pub fn renewing(self, renewal: Option<&RenewalInfo>, now: SystemTime) -> UserInfo {
+        if self.status != EntitlementStatus::Expired {
+            return self
+        }
+        let (status, grace_period_ends) = match renewal {
+            Some(renewal) if renewal.in_billing_retry => match renewal.grace_period_ends {
+                Some(ends) if ends > now => (EntitlementStatus::Grace, Some(ends)),
+                _ => (EntitlementStatus::BillingRetry, None)
+            },
+            _ => return self
+        };
+        UserInfo {
+            end_date: grace_period_ends.map_or(self.end_date, |ends| max(ends, self.end_date)),
+            status,
+            ..self
+        }
+    }
+}
+
+/// What the App Store says about renewing a subscription.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RenewalInfo {
+    pub original_transaction_id: String,
+    pub in_billing_retry: bool,
+    pub grace_period_ends: Option<SystemTime>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RenewalClaims {
+    #[serde(rename="originalTransactionId")]
+    original_transaction_id: String,
+    #[serde(rename="isInBillingRetryPeriod", default)]
+    is_in_billing_retry_period: bool,
+    #[serde(rename="gracePeriodExpiresDate", default)]
+    grace_period_expires_date: Option<BigDecimal>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -173,7 +423,13 @@ struct Claims {
     #[serde(rename="inAppOwnershipType")]
     in_app_ownership_type: String,
     #[serde(rename="originalPurchaseDate")]
-    original_purchase_date: BigDecimal
+    original_purchase_date: BigDecimal,
+    #[serde(rename="revocationDate", default)]
+    revocation_date: Option<BigDecimal>
+}
+
+fn millis_to_time(millis: &BigDecimal) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis.round(0).as_bigint_and_exponent().0.to_u64().unwrap())
 }
 
 pub fn verify_transaction(transaction_jws: String, pubkey: Vec<u8>) -> Result<UserInfo, Error> {
@@ -183,11 +439,33 @@ pub fn verify_transaction(transaction_jws: String, pubkey: Vec<u8>) -> Result<Us
     validation.validate_exp = false;
     let token_data = jsonwebtoken::decode::<Claims>(transaction_jws.as_str(), &decoding_key, &validation)?;
     println!("decoded claims: {:?}", token_data.claims);
-    let expires_date = SystemTime::UNIX_EPOCH + Duration::from_millis(token_data.claims.expires_date.round(0).as_bigint_and_exponent().0.to_u64().unwrap());
+    let expires_date = millis_to_time(&token_data.claims.expires_date);
+    // A refunded transaction ended when it was revoked.
+    let (end_date, status) = match &token_data.claims.revocation_date {
+        Some(revocation_date) => (expires_date.min(millis_to_time(revocation_date)), EntitlementStatus::Revoked),
+        None if expires_date > SystemTime::now() => (expires_date, EntitlementStatus::Active),
+        None => (expires_date, EntitlementStatus::Expired)
+    };
     Ok(UserInfo {
         id: token_data.claims.app_account_token,
+        original_transaction_id: token_data.claims.original_transaction_id,
         start_date: SystemTime::now(),
-        end_date: expires_date
+        end_date,
+        status
+    })
+}
+
+pub fn verify_renewal_info(renewal_info_jws: String, pubkey: Vec<u8>) -> Result<RenewalInfo, Error> {
+    let decoding_key = DecodingKey::from_ec_pem(&pubkey)?;
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.required_spec_claims = HashSet::new();
+    validation.validate_exp = false;
+    let token_data = jsonwebtoken::decode::<RenewalClaims>(renewal_info_jws.as_str(), &decoding_key, &validation)?;
+    println!("decoded renewal claims: {:?}", token_data.claims);
+    Ok(RenewalInfo {
+        original_transaction_id: token_data.claims.original_transaction_id,
+        in_billing_retry: token_data.claims.is_in_billing_retry_period,
+        grace_period_ends: token_data.claims.grace_period_expires_date.as_ref().map(millis_to_time)
     })
 }
 
@@ -245,6 +523,28 @@ pub fn transcode_to_concat(signature: &[u8]) -> Result<Vec<u8>, Error> {
     Ok(output)
 }
 
+
+/// Fails unless the entitlement and schedule tables, and the due queue if
+/// schedules are queued, match `selektor_common::schema`.
+pub async fn check_schema() -> Result<(), Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    let ddb_config = match env::var(DYNAMODB_ENDPOINT) {
+        Ok(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
+        _ => ddb::config::Builder::from(&config).build()
+    };
+    let ddb_client = ddb::Client::from_conf(ddb_config);
+    let mut tables = vec![
+        (env::var(ENTITLEMENTS_TABLE_NAME)?, schema::ENTITLEMENTS),
+        (env::var(SCHEDULE_TABLE_NAME)?, schema::SCHEDULES),
+        (env::var(ARCHIVE_TABLE_NAME)?, schema::ARCHIVED_SCHEDULES)
+    ];
+    if let Some(queue_table) = Scheduler::from_env()?.queue_table() {
+        tables.push((queue_table.to_string(), schema::DUE_QUEUE));
+    }
+    check_tables(&ddb_client, &tables).await
+}
+
 #[cfg(test)]
 const TEST_JWS: &str = "eyJraWQiOiJBcHBsZV9YY29kZV9LZXkiLCJ4NWMiOlsiTUlJQnpEQ0NBWEdnQXdJQkFnSUJBVEFLQmdncWhrak9QUVFEQWpCSU1TSXdJQVlEVlFRREV4bFRkRzl5WlV0cGRDQlVaWE4wYVc1bklHbHVJRmhqYjJSbE1TSXdJQVlEVlFRS0V4bFRkRzl5WlV0cGRDQlVaWE4wYVc1bklHbHVJRmhqYjJSbE1CNFhEVEl6TURFeU5UQTBOVFV6TjFvWERUSTBNREV5TlRBME5UVXpOMW93U0RFaU1DQUdBMVVFQXhNWlUzUnZjbVZMYVhRZ1ZHVnpkR2x1WnlCcGJpQllZMjlrWlRFaU1DQUdBMVVFQ2hNWlUzUnZjbVZMYVhRZ1ZHVnpkR2x1WnlCcGJpQllZMjlrWlRCWk1CTUdCeXFHU000OUFnRUdDQ3FHU000OUF3RUhBMElBQk9LT2FQd2NINjJHVUx2RzRNb3hmUDJMVXNpRVRpaWxSbGtFalNsY01lbUVZdlZUUWNEbEJHZjFKdndMa2l0eWlqNUdOa21ReFc3VHlFcFBBN3luSW5DalREQktNQklHQTFVZEV3RUJcL3dRSU1BWUJBZjhDQVFBd0pBWURWUjBSQkIwd0c0RVpVM1J2Y21WTGFYUWdWR1Z6ZEdsdVp5QnBiaUJZWTI5a1pUQU9CZ05WSFE4QkFmOEVCQU1DQjRBd0NnWUlLb1pJemowRUF3SURTUUF3UmdJaEFQUHdMSlp5bUZLR2xCK2RQdHUwOFlDZnIxXC9rOXVKY21hZkNBM3hINzNSMEFpRUEyckRkQVRZUUZRRmVveW0rbmpGcGRFMEtBN3B0MkE2Z245dm1pRVFnaFwvVT0iXSwidHlwIjoiSldUIiwiYWxnIjoiRVMyNTYifQ.eyJwcm9kdWN0SWQiOiJvcmcubWV0YXN0YXRpYy5zZWxla3Rvci5zdWJzY3JpcHRpb24ubW9udGhseSIsImVudmlyb25tZW50IjoiWGNvZGUiLCJxdWFudGl0eSI6MSwiYnVuZGxlSWQiOiJvcmcubWV0YXN0YXRpYy5TZWxla3RvciIsImFwcEFjY291bnRUb2tlbiI6IjRlMjk2N2VlLWEyMDctNGEwMC05YTMxLTRhNjA0NDNkNWU5NiIsIm9yaWdpbmFsVHJhbnNhY3Rpb25JZCI6IjAiLCJpc1VwZ3JhZGVkIjpmYWxzZSwiZXhwaXJlc0RhdGUiOjE2NzczMDA5MzcwNTAuMjk3MSwiZGV2aWNlVmVyaWZpY2F0aW9uTm9uY2UiOiI4YjUzMGFlNS0wYmIwLTQ2ZjQtYmJmZi0wOTc5MDM2MTg2MDkiLCJzaWduZWREYXRlIjoxNjc0NjIyNTM3MDc1LjkyMzgsInN1YnNjcmlwdGlvbkdyb3VwSWRlbnRpZmllciI6IjIxMTAwMjgyIiwicHVyY2hhc2VEYXRlIjoxNjc0NjIyNTM3MDUwLjI5NzEsInR5cGUiOiJBdXRvLVJlbmV3YWJsZSBTdWJzY3JpcHRpb24iLCJ0cmFuc2FjdGlvbklkIjoiMCIsIndlYk9yZGVyTGluZUl0ZW1JZCI6IjAiLCJkZXZpY2VWZXJpZmljYXRpb24iOiJoNTdyeFQyNlVpMzdwTUdpc3ZOR2xrV2E4U05jWDlYejJOMkdaRXlZZ2ZXVExObE5NTHNcL2xVb0ZrbGxUbjlmUiIsImluQXBwT3duZXJzaGlwVHlwZSI6IlBVUkNIQVNFRCIsIm9yaWdpbmFsUHVyY2hhc2VEYXRlIjoxNjc0NjIyNTM3MDUwLjI5NzF9.QrSL8WI2nVXq2dq3rvWGF1Ga187SDX9MrE2i6LI0gsP6KFB84rgyxfntkFxQS_3314AfxMdGnCyHNfvpVav5qQ";
 
@@ -253,6 +553,56 @@ fn test_verify() {
     let result = verify_transaction(String::from(TEST_JWS), XCODE_DEV_KEY.as_bytes().to_vec()).unwrap();
     assert_eq!(result.id, String::from("4e2967ee-a207-4a00-9a31-4a60443d5e96"));
     assert_eq!(result.end_date.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(), 1677300937050);
+    assert_eq!(result.status, EntitlementStatus::Expired);
+    assert_eq!(result.original_transaction_id, String::from("0"));
+}
+
+#[test]
+fn test_verify_renewal_info() {
+    // A transaction has no renewal claims of its own, so it reads as a
+    // subscription that isn't being retried.
+    let result = verify_renewal_info(String::from(TEST_JWS), XCODE_DEV_KEY.as_bytes().to_vec()).unwrap();
+    assert_eq!(result, RenewalInfo { original_transaction_id: String::from("0"), in_billing_retry: false, grace_period_ends: None });
+}
+
+#[test]
+fn test_renewing() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let ended = now - Duration::from_secs(3_600);
+    let user_info = |status| UserInfo {
+        id: String::from("user"),
+        original_transaction_id: String::from("0"),
+        start_date: now,
+        end_date: ended,
+        status
+    };
+    let renewal = |in_billing_retry, grace_period_ends| RenewalInfo {
+        original_transaction_id: String::from("0"),
+        in_billing_retry,
+        grace_period_ends
+    };
+
+    let grace_ends = now + Duration::from_secs(86_400);
+    let result = user_info(EntitlementStatus::Expired).renewing(Some(&renewal(true, Some(grace_ends))), now);
+    assert_eq!(result.status, EntitlementStatus::Grace);
+    assert_eq!(result.end_date, grace_ends);
+    // Past the grace period, the App Store keeps retrying for a while.
+    let result = user_info(EntitlementStatus::Expired).renewing(Some(&renewal(true, Some(ended))), now);
+    assert_eq!(result.status, EntitlementStatus::BillingRetry);
+    assert_eq!(result.end_date, ended);
+    let result = user_info(EntitlementStatus::Expired).renewing(Some(&renewal(true, None)), now);
+    assert_eq!(result.status, EntitlementStatus::BillingRetry);
+    let result = user_info(EntitlementStatus::Expired).renewing(Some(&renewal(false, None)), now);
+    assert_eq!(result.status, EntitlementStatus::Expired);
+    // Without signed renewal info, there's no telling.
+    let result = user_info(EntitlementStatus::Expired).renewing(None, now);
+    assert_eq!(result.status, EntitlementStatus::Expired);
+
+    // Only an expired entitlement can be renewing.
+    let result = user_info(EntitlementStatus::Revoked).renewing(Some(&renewal(true, Some(grace_ends))), now);
+    assert_eq!(result.status, EntitlementStatus::Revoked);
+    let result = user_info(EntitlementStatus::Active).renewing(Some(&renewal(true, None)), now);
+    assert_eq!(result.status, EntitlementStatus::Active);
 }
 
 #[test]
@@ -271,4 +621,36 @@ fn test_transcode_to_concat() {
     let sig = URL_SAFE_NO_PAD.decode("MEYCIQDFSUof9g5eIvO_hQR0ZknsAKyFAAvJyMkZff9_D5VM0QIhANR9PRoeyWNymaa05PIS9kdGe26MJRf10iiiGev1cQ0U").unwrap();
     let sig2 = transcode_to_concat(sig.as_ref()).unwrap();
     println!("transcoded: {}", STANDARD.encode(sig2));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_catch_up() {
+    let tick = Tick::new(60);
+    let now = 1_700_000_030;
+    let item = |catch_up: &str| Item::from([
+        (String::from("next_fire"), AttributeValue::N(String::from("1699990000"))),
+        (String::from("fire_interval"), AttributeValue::N(String::from("3600"))),
+        (String::from("catch_up"), AttributeValue::S(String::from(catch_up)))
+    ]);
+    // Not due yet, so it's left as it is.
+    let mut schedule = item("once");
+    assert_eq!(catch_up(&mut schedule, now + 60, 0, now, tick), Some((now + 60, Timing::Rate { start: now + 60, interval: 3600 })));
+    assert!(!schedule.contains_key("deferred_from"));
+
+    // Owed a push, which the next tick sends; the rate keeps its phase.
+    let mut schedule = item("once");
+    assert_eq!(catch_up(&mut schedule, 1_699_990_000, 0, now, tick), Some((1_700_000_040, Timing::Rate { start: 1_700_000_800, interval: 3600 })));
+    assert_eq!(schedule.get("next_fire"), Some(&AttributeValue::N(String::from("1700000040"))));
+    assert_eq!(schedule.get("deferred_from"), Some(&AttributeValue::N(String::from("1699990000"))));
+
+    // Nothing owed, so it carries on from its next occurrence.
+    let mut schedule = item("skip");
+    assert_eq!(catch_up(&mut schedule, 1_699_990_000, 0, now, tick), Some((1_700_000_800, Timing::Rate { start: 1_700_000_800, interval: 3600 })));
+    assert_eq!(schedule.get("next_fire"), Some(&AttributeValue::N(String::from("1700000800"))));
+    assert_eq!(schedule.get("missed_fires"), Some(&AttributeValue::N(String::from("3"))));
+
+    // One at a time, so the owed push is scheduled for the next tick.
+    let mut schedule = item("once");
+    schedule.insert(String::from("quiet_hours"), AttributeValue::S(String::from("[{\"start\": \"22:00\", \"end\": \"07:00\"}]")));
+    assert_eq!(catch_up(&mut schedule, 1_699_990_000, 0, now, tick), Some((1_700_000_040, Timing::At(1_700_000_040))));
+}
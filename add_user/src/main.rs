@@ -68,5 +68,6 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
+    lib::check_schema().await?;
     run(service_fn(function_handler)).await
 }
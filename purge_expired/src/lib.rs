@@ -5,24 +5,40 @@ use aws_sdk_dynamodb::model::AttributeValue;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
 use std::time::SystemTime;
 use tokio_stream::StreamExt;
+use selektor_common::entitlement::{EntitlementStatus, PurgeGrace};
+use selektor_common::schedule::version_key;
+use selektor_common::dynamodb::check_tables;
+use selektor_common::schema;
 
 const ENTITLEMENTS_TABLE_NAME: &str = "ENTITLEMENTS_TABLE_NAME";
 const SCHEDULE_TABLE_NAME: &str = "SCHEDULE_TABLE_NAME";
 const PARTITION_ID: &str = "PARTITION_ID";
 const DYNAMODB_ENDPOINT: &str = "DYNAMODB_ENDPOINT";
+const ARCHIVE_TABLE_NAME: &str = "ARCHIVE_TABLE_NAME";
+/// How long, in seconds, archived schedules are kept for `add_user` to
+/// restore. Defaults to 30 days.
+const ARCHIVE_RETENTION_SECONDS: &str = "ARCHIVE_RETENTION_SECONDS";
+const DEFAULT_ARCHIVE_RETENTION_SECONDS: u64 = 30 * 24 * 60 * 60;
 
-async fn delete_schedules(ddb_client: &ddb::Client, item: &HashMap<String, AttributeValue>) -> Result<(), Error> {
+/// Moves an entitlement's schedules to the archive, to expire `retention`
+/// seconds from `now`, unless `add_user` brings them back first. Each is put
+/// in the archive before it's deleted, so one that's interrupted is only
+/// ever in both, and is moved again next time.
+async fn archive_schedules(ddb_client: &ddb::Client, item: &HashMap<String, AttributeValue>, now: u64, retention: u64) -> Result<(), Error> {
     let schedule_table_name = env::var(SCHEDULE_TABLE_NAME)?;
+    let archive_table_name = env::var(ARCHIVE_TABLE_NAME)?;
     let partition_id = env::var(PARTITION_ID)?;
+    let expires = AttributeValue::N((now / 1000 + retention).to_string());
     if let Some(id) = item.get("id") {
         match id {
             AttributeValue::S(idval) => {
                 let mut schedules = ddb_client.query()
                     .set_table_name(Some(schedule_table_name.clone()))
-                    .set_index_name(Some(String::from("entitlement-index")))
-                    .set_key_condition_expression(Some(String::from("#part = :part AND #ent = :ent")))
+                    .set_index_name(Some(String::from(schema::ENTITLEMENT_INDEX.name)))
+                    .set_key_condition_expression(Some(String::from("#ent = :ent AND #part = :part")))
                     .set_expression_attribute_names(
                         Some(
                             HashMap::from([
@@ -34,13 +50,14 @@ async fn delete_schedules(ddb_client: &ddb::Client, item: &HashMap<String, Attri
                     .set_expression_attribute_values(
                         Some(
                             HashMap::from([
-                                (String::from(":part"), AttributeValue::S(partition_id)),
+                                (String::from(":part"), AttributeValue::S(partition_id.clone())),
                                 (String::from(":ent"), AttributeValue::S(idval.clone()))
                             ])
                         )
                     )
                     .into_paginator()
                     .send();
+                let mut archived = 0;
                 while let Some(res) = schedules.next().await {
                     match res?.items() {
                         Some(i) => for item in i {
@@ -50,6 +67,13 @@ async fn delete_schedules(ddb_client: &ddb::Client, item: &HashMap<String, Attri
                                         if let Some(id_val) = item.get("id") {
                                             match &id_val {
                                                 AttributeValue::S(id) => {
+                                                    let mut archived_item = item.clone();
+                                                    archived_item.insert(String::from("expires"), expires.clone());
+                                                    ddb_client.put_item()
+                                                        .set_table_name(Some(archive_table_name.clone()))
+                                                        .set_item(Some(archived_item))
+                                                        .send()
+                                                        .await?;
                                                     ddb_client.delete_item()
                                                         .set_table_name(Some(schedule_table_name.clone()))
                                                         .set_key(Some(
@@ -60,6 +84,7 @@ async fn delete_schedules(ddb_client: &ddb::Client, item: &HashMap<String, Attri
                                                         ))
                                                         .send()
                                                         .await?;
+                                                    archived += 1;
                                                 },
                                                 _ => println!("unexpected value for id {:#?}", id_val)
                                             }
@@ -72,6 +97,24 @@ async fn delete_schedules(ddb_client: &ddb::Client, item: &HashMap<String, Attri
                         None => break
                     }
                 }
+                if archived > 0 {
+                    // Their schedules changed, so an update made against
+                    // the old version mustn't go through.
+                    ddb_client.update_item()
+                        .set_table_name(Some(schedule_table_name))
+                        .set_key(Some(
+                            HashMap::from([
+                                (String::from("part"), AttributeValue::S(partition_id)),
+                                (String::from("id"), AttributeValue::S(version_key(idval)))
+                            ])
+                        ))
+                        .update_expression("ADD #version :one")
+                        .expression_attribute_names("#version", "schedule_version")
+                        .expression_attribute_values(":one", AttributeValue::N(String::from("1")))
+                        .send()
+                        .await?;
+                    println!("archived {} schedules for {}", archived, idval);
+                }
             },
             _ => println!("item id not a string {:#?}", item)
         }
@@ -79,6 +122,25 @@ async fn delete_schedules(ddb_client: &ddb::Client, item: &HashMap<String, Attri
     Ok(())
 }
 
+/// Whether an expired entitlement's schedules should go, going by its
+/// `status` and `ends`. One with a status this doesn't know is kept.
+fn purgeable(grace: &PurgeGrace, item: &HashMap<String, AttributeValue>, now: u64) -> bool {
+    let status = match item.get("status") {
+        Some(AttributeValue::S(status)) => match EntitlementStatus::from_str(status) {
+            Ok(status) => Some(status),
+            Err(_) => return false
+        },
+        _ => None
+    };
+    match item.get("ends") {
+        Some(AttributeValue::N(ends)) => match u64::from_str(ends) {
+            Ok(ends) => grace.purgeable(status, ends, now),
+            Err(_) => false
+        },
+        _ => false
+    }
+}
+
 pub async fn function_handler(_event: LambdaEvent<CloudWatchEvent>) -> Result<(), Error> {
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
     let config = aws_config::from_env().region(region_provider).load().await;
@@ -88,15 +150,21 @@ pub async fn function_handler(_event: LambdaEvent<CloudWatchEvent>) -> Result<()
     };
     let ddb_client = ddb::Client::from_conf(ddb_config);
     let entitlements_table_name = env::var(ENTITLEMENTS_TABLE_NAME)?;
+    let grace = PurgeGrace::from_env()?;
+    let retention = match env::var(ARCHIVE_RETENTION_SECONDS) {
+        Ok(seconds) => u64::from_str(&seconds)
+            .map_err(|_| format!("invalid {}: {}", ARCHIVE_RETENTION_SECONDS, seconds))?,
+        Err(_) => DEFAULT_ARCHIVE_RETENTION_SECONDS
+    };
     let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(d) => d.as_millis(),
+        Ok(d) => d.as_millis() as u64,
         Err(_) => 0
     };
     let partition_id = env::var(PARTITION_ID)?;
     let mut expired = ddb_client.query()
         .set_table_name(Some(entitlements_table_name))
-        .set_index_name(Some(String::from("ends-index")))
-        .set_key_condition_expression(Some(String::from("#part = :part AND #ends < :now")))
+        .set_index_name(Some(String::from(schema::ENDS_INDEX.name)))
+        .set_key_condition_expression(Some(String::from("#part = :part AND #ends < :cutoff")))
         .set_expression_attribute_names(
             Some(
                 HashMap::from([
@@ -109,7 +177,7 @@ pub async fn function_handler(_event: LambdaEvent<CloudWatchEvent>) -> Result<()
             Some(
                 HashMap::from([
                     (String::from(":part"), AttributeValue::S(partition_id.clone())),
-                    (String::from(":now"), AttributeValue::N(now.to_string()))
+                    (String::from(":cutoff"), AttributeValue::N(grace.cutoff(now).to_string()))
                 ])
             )
         )
@@ -118,10 +186,31 @@ pub async fn function_handler(_event: LambdaEvent<CloudWatchEvent>) -> Result<()
     while let Some(res) = expired.next().await {
         match res?.items() {
             Some(i) => for item in i {
-                delete_schedules(&ddb_client, item).await?;
+                if purgeable(&grace, item, now) {
+                    archive_schedules(&ddb_client, item, now, retention).await?;
+                } else {
+                    println!("keeping schedules for {:?}, status {:?}", item.get("id"), item.get("status"));
+                }
             },
             None => break
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Fails unless the entitlement, schedule and archive tables match
+/// `selektor_common::schema`.
+pub async fn check_schema() -> Result<(), Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    let ddb_config = match env::var(DYNAMODB_ENDPOINT) {
+        Ok(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
+        _ => ddb::config::Builder::from(&config).build()
+    };
+    let ddb_client = ddb::Client::from_conf(ddb_config);
+    check_tables(&ddb_client, &[
+        (env::var(ENTITLEMENTS_TABLE_NAME)?, schema::ENTITLEMENTS),
+        (env::var(SCHEDULE_TABLE_NAME)?, schema::SCHEDULES),
+        (env::var(ARCHIVE_TABLE_NAME)?, schema::ARCHIVED_SCHEDULES)
+    ]).await
+}
@@ -20,5 +20,6 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
+    lib::check_schema().await?;
     run(service_fn(lib::function_handler)).await
 }
@@ -6,20 +6,132 @@ use serde::{Deserialize, Serialize};
 use std::env;
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_dynamodb::model::AttributeValue;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use lambda_http::aws_lambda_events::http_body::Body;
+use selektor_common::payload::Platform;
+use selektor_common::dynamodb::check_tables;
+use selektor_common::schema;
 
 const PUSH_TABLE_NAME: &str = "PUSH_TABLE_NAME";
 const DYNAMODB_ENDPOINT: &str = "DYNAMODB_ENDPOINT";
 const SNS_APP_ARN: &str = "SNS_APP_ARN";
+const SNS_SANDBOX_APP_ARN: &str = "SNS_SANDBOX_APP_ARN";
+
+/// The kind of device a push row delivers to. Stored on the push row as
+/// `device_type`; rows without one predate web push and are APNs.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    #[default]
+    Apns,
+    WebPush
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Apns => "apns",
+            DeviceType::WebPush => "web_push"
+        }
+    }
+}
+
+/// Which APNs environment an iOS device's token belongs to. Xcode and
+/// TestFlight builds get sandbox tokens, which the production APNs rejects.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ApnsEnvironment {
+    #[default]
+    Production,
+    Sandbox
+}
+
+impl ApnsEnvironment {
+    fn platform(&self) -> Platform {
+        match self {
+            ApnsEnvironment::Production => Platform::Apns,
+            ApnsEnvironment::Sandbox => Platform::ApnsSandbox
+        }
+    }
+
+    /// The environment variable naming the SNS platform application for this environment.
+    fn app_arn_var(&self) -> &'static str {
+        match self {
+            ApnsEnvironment::Production => SNS_APP_ARN,
+            ApnsEnvironment::Sandbox => SNS_SANDBOX_APP_ARN
+        }
+    }
+}
+
+/// The keys of a browser `PushSubscription`, base64url encoded.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebPushKeys {
+    p256dh: String,
+    auth: String
+}
+
+/// A browser `PushSubscription`, in the shape returned by `toJSON()`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebPushSubscription {
+    endpoint: String,
+    keys: WebPushKeys
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterPushRequest {
-    push_token: String
+    #[serde(default)]
+    device_type: DeviceType,
+    #[serde(default)]
+    push_token: Option<String>,
+    #[serde(default)]
+    apns_environment: ApnsEnvironment,
+    #[serde(default)]
+    subscription: Option<WebPushSubscription>
+}
+
+/// A request that can't be registered as it is, which the caller has to fix.
+#[derive(Debug)]
+pub struct RegisterPushError {
+    pub reason: String
+}
+
+impl std::error::Error for RegisterPushError {}
+
+impl std::fmt::Display for RegisterPushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+enum Device {
+    Apns(String, ApnsEnvironment),
+    WebPush(WebPushSubscription)
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::from(RegisterPushError { reason: reason.to_string() })
+}
+
+/// Checks that a web push subscription has an https endpoint, an uncompressed
+/// P-256 public key and a 16 byte auth secret.
+fn validate_subscription(subscription: &WebPushSubscription) -> Result<(), Error> {
+    if !subscription.endpoint.starts_with("https://") {
+        return Err(invalid("subscription endpoint must be an https URL"))
+    }
+    match URL_SAFE_NO_PAD.decode(subscription.keys.p256dh.trim_end_matches('=')) {
+        Ok(key) if key.len() == 65 && key[0] == 4 => {}
+        _ => return Err(invalid("p256dh must be an uncompressed P-256 public key"))
+    }
+    match URL_SAFE_NO_PAD.decode(subscription.keys.auth.trim_end_matches('=')) {
+        Ok(auth) if auth.len() == 16 => {}
+        _ => return Err(invalid("auth must be a 16 byte secret"))
+    }
+    Ok(())
 }
 
 pub async fn register_push(principal: &String, request: RegisterPushRequest) -> Result<(), Error> {
     let table_name = env::var(PUSH_TABLE_NAME)?;
-    let sns_app_arn = env::var(SNS_APP_ARN)?;
 
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
     let config = aws_config::from_env().region(region_provider).load().await;
@@ -30,6 +142,20 @@ pub async fn register_push(principal: &String, request: RegisterPushRequest) ->
     let ddb_client = ddb::Client::from_conf(ddb_config);
     let sns_client = sns::Client::new(&config);
 
+    let device = match request.device_type {
+        DeviceType::Apns => match request.push_token {
+            Some(token) => Device::Apns(token, request.apns_environment),
+            None => return Err(invalid("push_token is required for apns devices"))
+        },
+        DeviceType::WebPush => match request.subscription {
+            Some(subscription) => {
+                validate_subscription(&subscription)?;
+                Device::WebPush(subscription)
+            },
+            None => return Err(invalid("subscription is required for web_push devices"))
+        }
+    };
+
     let existing_data = ddb_client.get_item()
         .set_table_name(Some(table_name.to_owned()))
         .set_key(Some(HashMap::from([("id".to_string(), AttributeValue::S(principal.to_string()))])))
@@ -44,8 +170,13 @@ pub async fn register_push(principal: &String, request: RegisterPushRequest) ->
                         .set_endpoint_arn(Some(arn.to_owned()))
                         .send()
                         .await?;
+                    let existing_platform = match item.get("platform") {
+                        Some(AttributeValue::S(platform)) => Platform::from_key(platform),
+                        _ => Some(Platform::Apns)
+                    };
                     let delete = match endpoint.attributes.and_then(|m| { m.get("Token").cloned() }) {
-                        Some(token) => if token.eq(&(request.push_token)) {
+                        Some(token) => if matches!(&device, Device::Apns(push_token, environment)
+                            if token.eq(push_token) && existing_platform == Some(environment.platform())) {
                             // Token already exists, skip anything else.
                             return Ok(())
                         } else {
@@ -66,9 +197,28 @@ pub async fn register_push(principal: &String, request: RegisterPushRequest) ->
         None => {}
     }
 
+    let (push_token, environment) = match device {
+        Device::Apns(push_token, environment) => (push_token, environment),
+        Device::WebPush(subscription) => {
+            ddb_client.put_item()
+                .set_table_name(Some(table_name.to_owned()))
+                .set_item(Some(HashMap::from([
+                    ("id".to_string(), AttributeValue::S(principal.to_owned())),
+                    ("device_type".to_string(), AttributeValue::S(DeviceType::WebPush.as_str().to_string())),
+                    ("endpoint".to_string(), AttributeValue::S(subscription.endpoint)),
+                    ("p256dh".to_string(), AttributeValue::S(subscription.keys.p256dh)),
+                    ("auth".to_string(), AttributeValue::S(subscription.keys.auth))
+                ])))
+                .send()
+                .await?;
+            return Ok(())
+        }
+    };
+
+    let sns_app_arn = env::var(environment.app_arn_var())?;
     let endpoint_result = sns_client.create_platform_endpoint()
         .set_platform_application_arn(Some(sns_app_arn))
-        .set_token(Some(request.push_token))
+        .set_token(Some(push_token))
         .send()
         .await?;
 
@@ -78,6 +228,8 @@ pub async fn register_push(principal: &String, request: RegisterPushRequest) ->
                 .set_table_name(Some(table_name.to_owned()))
                 .set_item(Some(HashMap::from([
                     ("id".to_string(), AttributeValue::S(principal.to_owned())),
+                    ("device_type".to_string(), AttributeValue::S(DeviceType::Apns.as_str().to_string())),
+                    ("platform".to_string(), AttributeValue::S(environment.platform().key().to_string())),
                     ("endpoint_arn".to_string(), AttributeValue::S(arn.to_string()))
                 ])))
                 .send()
@@ -87,4 +239,51 @@ pub async fn register_push(principal: &String, request: RegisterPushRequest) ->
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+/// Fails unless the push table matches `selektor_common::schema`.
+pub async fn check_schema() -> Result<(), Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    let ddb_config = match env::var(DYNAMODB_ENDPOINT) {
+        Ok(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
+        _ => ddb::config::Builder::from(&config).build()
+    };
+    let ddb_client = ddb::Client::from_conf(ddb_config);
+    check_tables(&ddb_client, &[(env::var(PUSH_TABLE_NAME)?, schema::PUSH)]).await
+}
+
+#[test]
+fn test_legacy_request_is_apns() {
+    let request: RegisterPushRequest = serde_json::from_str("{\"push_token\":\"abcd\"}").unwrap();
+    assert_eq!(request.device_type, DeviceType::Apns);
+    assert_eq!(request.push_token, Some(String::from("abcd")));
+    assert_eq!(request.apns_environment, ApnsEnvironment::Production);
+}
+
+#[test]
+fn test_sandbox_request() {
+    let request: RegisterPushRequest = serde_json::from_str(
+        "{\"push_token\":\"abcd\",\"apns_environment\":\"sandbox\"}"
+    ).unwrap();
+    assert_eq!(request.apns_environment.platform(), Platform::ApnsSandbox);
+    assert_eq!(request.apns_environment.app_arn_var(), SNS_SANDBOX_APP_ARN);
+}
+
+#[test]
+fn test_validate_subscription() {
+    let request: RegisterPushRequest = serde_json::from_str(
+        "{\"device_type\":\"web_push\",\"subscription\":{\"endpoint\":\"https://push.example.com/abc\",\"keys\":{\
+        \"p256dh\":\"BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4\",\
+        \"auth\":\"BTBZMqHH6r4Tts7J_aSIgg\"}}}"
+    ).unwrap();
+    assert_eq!(request.device_type, DeviceType::WebPush);
+    let mut subscription = request.subscription.unwrap();
+    assert!(validate_subscription(&subscription).is_ok());
+    subscription.keys.auth = String::from("c2hvcnQ");
+    assert!(validate_subscription(&subscription).is_err());
+    subscription.keys.auth = String::from("BTBZMqHH6r4Tts7J_aSIgg");
+    subscription.endpoint = String::from("http://push.example.com/abc");
+    assert!(validate_subscription(&subscription).is_err());
+}
@@ -1,10 +1,8 @@
-mod lib;
-
 use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response};
 use lambda_http::request::RequestContext;
 use serde_json::Value;
 use tracing::info;
-use register_push::{RegisterPushRequest, register_push};
+use register_push::{RegisterPushError, RegisterPushRequest, register_push};
 
 /// This is the main body for the function.
 /// Write your code inside it.
@@ -29,11 +27,30 @@ async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
                     };
 
                     info!("register_push {:?}", request);
-                    register_push(principal, request?).await?;
-                    Response::builder()
-                        .status(204)
-                        .body(Body::Empty)
-                        .map_err(Box::new)?
+                    let request = match request {
+                        Ok(request) => request,
+                        Err(e) => return Ok(
+                            Response::builder()
+                                .status(400)
+                                .header("content-type", "text/plain")
+                                .body(e.to_string().into())
+                                .map_err(Box::new)?
+                        )
+                    };
+                    match register_push(principal, request).await {
+                        Ok(()) => Response::builder()
+                            .status(204)
+                            .body(Body::Empty)
+                            .map_err(Box::new)?,
+                        Err(e) => match e.downcast_ref::<RegisterPushError>() {
+                            Some(invalid) => Response::builder()
+                                .status(400)
+                                .header("content-type", "text/plain")
+                                .body(invalid.to_string().into())
+                                .map_err(Box::new)?,
+                            None => return Err(e)
+                        }
+                    }
                 },
                 _ => Response::builder()
                     .status(401)
@@ -62,5 +79,6 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
+    register_push::check_schema().await?;
     run(service_fn(function_handler)).await
 }
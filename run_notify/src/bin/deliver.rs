@@ -0,0 +1,26 @@
+use lambda_runtime::{Error, run, service_fn};
+use std::env;
+
+const TRACING_DEBUG: &str = "TRACING_DEBUG";
+
+/// Invoked by EventBridge Scheduler to send one schedule's push; see `run_notify::deliver`.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(match env::var(TRACING_DEBUG) {
+            Ok(_) => tracing::Level::DEBUG,
+            Err(_) => tracing::Level::INFO
+        })
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run_notify::check_schema().await?;
+    let push_scheduler = match run_notify::push_scheduler().await? {
+        Some(push_scheduler) => push_scheduler,
+        None => return Err(Error::from("the deliver function needs SCHEDULER=eventbridge"))
+    };
+    run(service_fn(|event| run_notify::deliver::deliver_handler(event, push_scheduler.as_ref()))).await
+}
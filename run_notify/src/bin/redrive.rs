@@ -0,0 +1,22 @@
+use lambda_runtime::{Error, run, service_fn};
+use std::env;
+
+const TRACING_DEBUG: &str = "TRACING_DEBUG";
+
+/// Manually invoked to replay the dead-letter table; see `run_notify::dead_letter`.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(match env::var(TRACING_DEBUG) {
+            Ok(_) => tracing::Level::DEBUG,
+            Err(_) => tracing::Level::INFO
+        })
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run_notify::check_schema().await?;
+    run(service_fn(run_notify::dead_letter::redrive_handler)).await
+}
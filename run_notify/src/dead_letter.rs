@@ -0,0 +1,179 @@
+use crate::{near_deadline, Item, Notifier};
+use aws_sdk_dynamodb as ddb;
+use aws_sdk_dynamodb::model::AttributeValue;
+use futures::StreamExt;
+use lambda_runtime::{Error, LambdaEvent};
+use selektor_common::payload::PayloadTemplate;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+pub const DEAD_LETTER_TABLE_NAME: &str = "DEAD_LETTER_TABLE_NAME";
+/// How long a dead letter is kept after it last failed, via the table's TTL.
+const DEAD_LETTER_RETENTION: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// A push that still failed after retrying, kept so it can be redriven.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    /// `<entitlement>#<bucket>#<occurrence>#<hash>`, unique per push that was
+    /// due; the hash is of `schedule_id`, since the deliver function sends
+    /// one push per schedule, so several can share the rest in a tick.
+    pub id: String,
+    /// The schedules the push was for, comma separated.
+    pub schedule_id: String,
+    pub entitlement: String,
+    pub payload: PayloadTemplate,
+    pub error: String,
+    pub attempts: u32
+}
+
+impl DeadLetter {
+    fn to_item(&self) -> Item {
+        let failed_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs();
+        HashMap::from([
+            ("id".to_string(), AttributeValue::S(self.id.to_owned())),
+            ("schedule_id".to_string(), AttributeValue::S(self.schedule_id.to_owned())),
+            ("entitlement".to_string(), AttributeValue::S(self.entitlement.to_owned())),
+            ("payload".to_string(), AttributeValue::S(serde_json::to_string(&self.payload).unwrap_or_default())),
+            ("error".to_string(), AttributeValue::S(self.error.to_owned())),
+            ("attempts".to_string(), AttributeValue::N(self.attempts.to_string())),
+            ("failed_at".to_string(), AttributeValue::N(failed_at.to_string())),
+            ("expires".to_string(), AttributeValue::N((failed_at + DEAD_LETTER_RETENTION.as_secs()).to_string()))
+        ])
+    }
+
+    fn from_item(item: &Item) -> Option<DeadLetter> {
+        match (
+            item.get("id"), item.get("schedule_id"), item.get("entitlement"),
+            item.get("payload"), item.get("error"), item.get("attempts")
+        ) {
+            (
+                Some(AttributeValue::S(id)), Some(AttributeValue::S(schedule_id)), Some(AttributeValue::S(entitlement)),
+                Some(AttributeValue::S(payload)), Some(AttributeValue::S(error)), Some(AttributeValue::N(attempts))
+            ) => Some(DeadLetter {
+                id: id.to_string(),
+                schedule_id: schedule_id.to_string(),
+                entitlement: entitlement.to_string(),
+                payload: serde_json::from_str(payload).ok()?,
+                error: error.to_string(),
+                attempts: u32::from_str(attempts).ok()?
+            }),
+            _ => None
+        }
+    }
+}
+
+/// The dead-letter table.
+pub struct DeadLetters {
+    ddb_client: ddb::Client,
+    table_name: String
+}
+
+impl DeadLetters {
+    pub fn new(ddb_client: ddb::Client, table_name: String) -> DeadLetters {
+        DeadLetters { ddb_client, table_name }
+    }
+
+    pub async fn put(&self, dead_letter: &DeadLetter) -> Result<(), Error> {
+        warn!("dead-lettering {} after {} attempts: {}", dead_letter.id, dead_letter.attempts, dead_letter.error);
+        self.ddb_client.put_item()
+            .table_name(self.table_name.to_owned())
+            .set_item(Some(dead_letter.to_item()))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        self.ddb_client.delete_item()
+            .table_name(self.table_name.to_owned())
+            .key("id", AttributeValue::S(id.to_string()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Replays everything in the dead-letter table through the same publish path
+/// `run_notify` uses, against the devices' current push rows and within the
+/// same rate limits. Delivered pushes are removed; ones that fail again, or
+/// are over the limits, stay, with their attempts added up.
+pub async fn redrive_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let deadline = match event.context.deadline {
+        0 => None,
+        millis => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    };
+    let notifier = Notifier::from_env(None).await?;
+    let mut pages = notifier.ddb_client.scan()
+        .table_name(notifier.dead_letters.table_name.to_owned())
+        .into_paginator()
+        .send();
+    let mut redriven = 0;
+    let mut failed = 0;
+    while let Some(page) = pages.next().await {
+        let page = page?;
+        let items = match page.items() {
+            Some(items) => items,
+            None => break
+        };
+        let pushes = notifier.fetch_pushes(items).await?;
+        for item in items {
+            if near_deadline(deadline) {
+                info!("deadline approaching, redrove {}, {} failed again", redriven, failed);
+                return Ok(json!({"redriven": redriven, "failed": failed, "complete": false}))
+            }
+            let dead_letter = match DeadLetter::from_item(item) {
+                Some(dead_letter) => dead_letter,
+                None => {
+                    warn!("skipping invalid dead letter: {:?}", item);
+                    continue
+                }
+            };
+            let result = match pushes.get(&dead_letter.entitlement) {
+                Some(_) if !notifier.admit(&dead_letter.entitlement).await => Err(("over the rate limit".to_string(), 0)),
+                Some(push) => notifier.deliver(&dead_letter.schedule_id, &dead_letter.entitlement, &dead_letter.payload, push).await
+                    .map_err(|(e, attempts)| (e.reason, attempts)),
+                None => Err((format!("no push entry for {}", dead_letter.entitlement), 1))
+            };
+            match result {
+                Ok(()) => {
+                    notifier.dead_letters.delete(&dead_letter.id).await?;
+                    redriven += 1;
+                },
+                Err((error, attempts)) => {
+                    notifier.dead_letters.put(&DeadLetter {
+                        error,
+                        attempts: dead_letter.attempts + attempts,
+                        ..dead_letter
+                    }).await?;
+                    failed += 1;
+                }
+            }
+        }
+    }
+    info!("redrove {}, {} failed again", redriven, failed);
+    Ok(json!({"redriven": redriven, "failed": failed, "complete": true}))
+}
+
+#[test]
+fn test_item_round_trip() {
+    let dead_letter = DeadLetter {
+        id: "abc#100#0".to_string(),
+        schedule_id: "abc".to_string(),
+        entitlement: "ent".to_string(),
+        payload: PayloadTemplate { body: Some("hello".to_string()), ..Default::default() },
+        error: "throttled".to_string(),
+        attempts: 4
+    };
+    let item = dead_letter.to_item();
+    assert_eq!(DeadLetter::from_item(&item), Some(dead_letter));
+    let number = |name: &str| match item.get(name) {
+        Some(AttributeValue::N(n)) => u64::from_str(n).unwrap(),
+        _ => panic!("no {}", name)
+    };
+    assert_eq!(number("expires"), number("failed_at") + DEAD_LETTER_RETENTION.as_secs());
+}
@@ -0,0 +1,73 @@
+use crate::{Item, Notifier, Outcome};
+use selektor_common::push_scheduler::PushScheduler;
+use aws_sdk_dynamodb::model::AttributeValue;
+use lambda_runtime::{Error, LambdaEvent};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+/// Reads a schedule row, consistently, since it was likely just claimed.
+async fn schedule(notifier: &Notifier<'_>, id: &str) -> Result<Option<Item>, Error> {
+    let output = notifier.ddb_client.get_item()
+        .table_name(notifier.table_name.to_owned())
+        .key("part", AttributeValue::S(notifier.partition_id.to_owned()))
+        .key("id", AttributeValue::S(id.to_owned()))
+        .consistent_read(true)
+        .send()
+        .await?;
+    Ok(output.item().cloned())
+}
+
+fn next_fire(item: &Item) -> Option<u64> {
+    match item.get("next_fire") {
+        Some(AttributeValue::N(next_fire)) => u64::from_str(next_fire).ok(),
+        _ => None
+    }
+}
+
+/// Invoked by a schedule's EventBridge schedule, with `{"id": <row id>}`,
+/// when `SCHEDULER` is `eventbridge`. Fires the schedule if it's due, the
+/// same way a tick would, then makes sure its EventBridge schedule is set for
+/// its next fire, or removed if the row is gone or won't fire again. One
+/// that isn't due, because it was sent already or moved, isn't sent.
+pub async fn deliver_handler(event: LambdaEvent<Value>, push_scheduler: &dyn PushScheduler) -> Result<Value, Error> {
+    let deadline = match event.context.deadline {
+        0 => None,
+        millis => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    };
+    let id = match event.payload.get("id") {
+        Some(Value::String(id)) => id.to_string(),
+        _ => return Err(Error::from(format!("no schedule id in {}", event.payload)))
+    };
+    let notifier = Notifier::from_env(Some(push_scheduler)).await?;
+    let item = match schedule(&notifier, &id).await? {
+        Some(item) => item,
+        None => {
+            info!("{} is gone, removing its schedule", id);
+            notifier.reschedule(&id, &Item::new(), None).await?;
+            return Ok(json!({"outcome": "removed"}))
+        }
+    };
+    let (outcome, sent) = match next_fire(&item) {
+        Some(due) if due < notifier.tick.next(notifier.fire_time) => {
+            let pushes = notifier.fetch_pushes(std::slice::from_ref(&item)).await?;
+            let outcome = notifier.notify(&item, &pushes, deadline).await?;
+            if outcome == Outcome::Deferred {
+                return Err(Error::from(format!("ran out of time before claiming {}", id)))
+            }
+            let sent = notifier.deliver_due(1, deadline).await?;
+            notifier.deliver_coalesced(deadline).await?;
+            (format!("{:?}", outcome), sent)
+        },
+        _ => ("NotDue".to_string(), 0)
+    };
+    // Claiming it rescheduled it already, unless that failed, or something
+    // else claimed it first.
+    match schedule(&notifier, &id).await? {
+        Some(current) => notifier.reschedule(&id, &current, next_fire(&current)).await?,
+        None => notifier.reschedule(&id, &item, None).await?
+    }
+    info!("{} for {}, sent {} pushes", outcome, id, sent);
+    Ok(json!({"outcome": outcome, "sent": sent}))
+}
@@ -1,124 +1,1090 @@
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::SdkConfig;
 use aws_sdk_dynamodb as ddb;
-use aws_sdk_dynamodb::model::AttributeValue;
+use aws_sdk_dynamodb::error::{TransactWriteItemsErrorKind, TransactionCanceledException};
+use aws_sdk_dynamodb::model::{AttributeValue, KeysAndAttributes, Put, TransactWriteItem, Update};
 use aws_sdk_sns as sns;
 use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;
+use futures::StreamExt;
 use lambda_runtime::LambdaEvent;
 use lambda_runtime::Error;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
 use aws_sdk_sns::model::MessageAttributeValue;
-use tokio_stream::StreamExt;
+use selektor_common::local_time::{quiet_until, QuietHours};
+use selektor_common::payload::{PayloadTemplate, Platform};
+use selektor_common::push_scheduler::{self, schedule_name, PushSchedule, PushScheduler, Timing};
+use selektor_common::dynamodb::check_tables;
+use selektor_common::schema;
+use selektor_common::schedule_item;
+use selektor_common::schedule::{
+    is_legacy_bucket, queue_key, swept_key, CatchUp, CatchUpPolicy, Jitter, Recurrence, Scheduler, Shards, Tick,
+    stable_hash, DUE_QUEUE_TABLE_NAME, QUEUE_RETENTION_SECONDS
+};
 use tracing::{debug, error, info, warn};
+use aws_sdk_sns::types::SdkError;
+use chrono_tz::Tz;
+use dead_letter::{DeadLetter, DeadLetters, DEAD_LETTER_TABLE_NAME};
+use rand::Rng;
+use rate_limit::{RateLimiter, RATE_LIMIT_TABLE_NAME};
+use serde_json::json;
+use web_push::{Delivery, VapidKey, WebPushSender, WebPushSubscription};
+
+pub mod dead_letter;
+pub mod deliver;
+pub mod rate_limit;
+pub mod web_push;
 
-const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
 const TABLE_NAME: &str = "TABLE_NAME";
 const PARTITION_ID: &str = "PARTITION_ID";
 const PUSH_TABLE_NAME: &str = "PUSH_TABLE_NAME";
+const FIRED_TABLE_NAME: &str = "FIRED_TABLE_NAME";
+const NOTIFY_PARALLELISM: &str = "NOTIFY_PARALLELISM";
+const DYNAMODB_ENDPOINT: &str = "DYNAMODB_ENDPOINT";
 
-pub async fn function_handler(event: LambdaEvent<CloudWatchEvent>) -> Result<(), Error> {
-    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
-    let config = aws_config::from_env().region(region_provider).load().await;
-    let ddb_client = ddb::Client::new(&config);
-    let fire_time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => n.as_millis() / FIVE_MINUTES.as_millis(),
-        Err(_) => 0
+const DEFAULT_PARALLELISM: usize = 16;
+/// BatchGetItem accepts at most this many keys per request.
+const BATCH_GET_LIMIT: usize = 100;
+/// Stop starting new notifications once the invocation is this close to its
+/// deadline. Anything left over is still due, and is picked up next tick.
+const DEADLINE_MARGIN: Duration = Duration::from_secs(10);
+/// How long (schedule, bucket) idempotency records are kept, via the table's TTL.
+/// Long past any retry or overlapping invocation for the same bucket.
+const FIRED_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Attempts at publishing a push before it's dead-lettered.
+const MAX_PUBLISH_ATTEMPTS: u32 = 4;
+const PUBLISH_BACKOFF_BASE: Duration = Duration::from_millis(100);
+const PUBLISH_BACKOFF_MAX: Duration = Duration::from_secs(2);
+/// The `id` of the due queue row holding the last tick that was swept.
+const SWEPT_ID: &str = "swept";
+/// Attempts at claiming a schedule whose claim was canceled by contention
+/// rather than by its bucket being claimed.
+const MAX_CLAIM_ATTEMPTS: u32 = 3;
+
+type Item = HashMap<String, AttributeValue>;
+
+/// A DynamoDB client, for `DYNAMODB_ENDPOINT` if it's set.
+fn ddb_client(config: &SdkConfig) -> ddb::Client {
+    match env::var(DYNAMODB_ENDPOINT) {
+        Ok(endpoint) => ddb::Client::from_conf(ddb::config::Builder::from(config).endpoint_url(endpoint).build()),
+        _ => ddb::Client::new(config)
+    }
+}
+
+/// The platform of a push row's SNS endpoint. Rows from before sandbox
+/// routing don't record one, and were all registered against production APNs.
+fn decode_platform(item: &Item) -> Platform {
+    match item.get("platform") {
+        Some(AttributeValue::S(key)) => Platform::from_key(key).unwrap_or_else(|| {
+            warn!("unknown platform {}, assuming APNS", key);
+            Platform::Apns
+        }),
+        _ => Platform::Apns
+    }
+}
+
+/// Reads the schedule's payload template, falling back to a background push.
+fn decode_payload(item: &Item) -> PayloadTemplate {
+    match item.get("payload") {
+        Some(AttributeValue::S(json)) => serde_json::from_str(json).unwrap_or_else(|e| {
+            warn!("invalid payload template {}, sending a background push: {}", json, e);
+            PayloadTemplate::default()
+        }),
+        _ => PayloadTemplate::default()
+    }
+}
+
+/// Reads the schedule's catch-up policy; schedules without one fire once.
+fn decode_catch_up(item: &Item) -> CatchUpPolicy {
+    schedule_item::catch_up(item).unwrap_or_else(|e| {
+        warn!("{}, firing once", e);
+        CatchUpPolicy::default()
+    })
+}
+
+/// Reads the schedule's time zone, defaulting to UTC.
+fn decode_time_zone(item: &Item) -> Tz {
+    schedule_item::time_zone(item).unwrap_or_else(|e| {
+        warn!("{}, using UTC", e);
+        Tz::UTC
+    })
+}
+
+fn decode_quiet_hours(item: &Item) -> Vec<QuietHours> {
+    schedule_item::quiet_hours(item).unwrap_or_else(|e| {
+        warn!("ignoring {}", e);
+        Vec::new()
+    })
+}
+
+/// Reads how the schedule repeats; see `schedule_item::recurrence`.
+fn decode_recurrence(item: &Item, offset: u64) -> Option<Recurrence> {
+    schedule_item::recurrence(item, offset)
+        .map_err(|e| error!("{}", e))
+        .ok()
+        .flatten()
+}
+
+/// Whether there's too little time left before `deadline` to start more work.
+/// A missing deadline (e.g. when run locally) never expires.
+fn near_deadline(deadline: Option<SystemTime>) -> bool {
+    match deadline {
+        Some(deadline) => match deadline.duration_since(SystemTime::now()) {
+            Ok(remaining) => remaining < DEADLINE_MARGIN,
+            Err(_) => true
+        },
+        None => false
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Processed,
+    /// Another invocation already claimed this schedule's bucket.
+    AlreadyClaimed,
+    /// The schedule item can't be advanced, so it isn't sent either.
+    Invalid,
+    /// Not started because the invocation ran out of time, or couldn't be
+    /// claimed for contention; it's left for the next tick.
+    Deferred
+}
+
+/// A failed attempt at publishing a push.
+#[derive(Debug)]
+struct PublishError {
+    reason: String,
+    /// Whether trying again might succeed, e.g. throttling or a 5xx.
+    transient: bool
+}
+
+impl PublishError {
+    fn transient(reason: String) -> PublishError {
+        PublishError { reason, transient: true }
+    }
+
+    fn permanent(reason: String) -> PublishError {
+        PublishError { reason, transient: false }
+    }
+}
+
+impl std::fmt::Display for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Whether an SNS publish failed in a way worth retrying.
+fn is_transient(e: &SdkError<sns::error::PublishError>) -> bool {
+    match e {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(context) => {
+            matches!(context.err().code(), Some("Throttling") | Some("ThrottlingException"))
+                || context.err().is_kms_throttling_exception()
+                || context.err().is_internal_error_exception()
+                || context.raw().http().status().is_server_error()
+        },
+        _ => false
+    }
+}
+
+/// How long to wait before retrying after `attempts` failures: a random
+/// duration up to an exponentially growing cap ("full jitter").
+fn backoff(attempts: u32) -> Duration {
+    let cap = PUBLISH_BACKOFF_BASE.saturating_mul(1 << attempts.min(16)).min(PUBLISH_BACKOFF_MAX);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=cap.as_millis() as u64))
+}
+
+/// The pushes for a principal that were over the rate limit this tick.
+struct Coalesced {
+    push: Item,
+    schedule_ids: Vec<String>
+}
+
+/// The single push sent in place of a principal's over-limit pushes: a
+/// background push listing the schedules that were due, so the app can
+/// refresh them all.
+fn coalesced_payload(schedule_ids: &[String]) -> PayloadTemplate {
+    let schedule_ids: Vec<&str> = schedule_ids.iter().map(String::as_str).collect();
+    with_schedule_ids(PayloadTemplate::default(), &schedule_ids)
+}
+
+/// Lists `schedule_ids` in the payload's `schedule_ids`, as many of them as
+/// fit in a push. When they don't all fit, the first ones that do are sent,
+/// along with how many there are in all as `schedule_count`.
+fn with_schedule_ids(mut payload: PayloadTemplate, schedule_ids: &[&str]) -> PayloadTemplate {
+    payload.data.insert("schedule_ids".to_string(), json!(schedule_ids));
+    if payload.validate().is_ok() {
+        return payload
+    }
+    payload.data.insert("schedule_count".to_string(), json!(schedule_ids.len()));
+    // The most that fit, found by bisecting; with none, the template
+    // itself is too big, which is caught when it's sent.
+    let (mut fit, mut too_many) = (0, schedule_ids.len());
+    while too_many - fit > 1 {
+        let mid = (fit + too_many) / 2;
+        payload.data.insert("schedule_ids".to_string(), json!(&schedule_ids[..mid]));
+        if payload.validate().is_ok() {
+            fit = mid;
+        } else {
+            too_many = mid;
+        }
+    }
+    payload.data.insert("schedule_ids".to_string(), json!(&schedule_ids[..fit]));
+    payload
+}
+
+/// A claimed schedule, waiting to go out with the others due for its device.
+struct Due {
+    schedule_id: String,
+    payload: PayloadTemplate,
+    fires: u64
+}
+
+/// The schedules due for one device this tick.
+struct DueDevice {
+    push: Item,
+    due: Vec<Due>
+}
+
+/// The payload for one push covering several due schedules. If they share a
+/// template it's used as is; otherwise the first alert among them, by
+/// schedule ID, is shown, or it's a background push if there's none. Either
+/// way `schedule_ids` lists the schedules the push is for; see
+/// `with_schedule_ids`.
+fn grouped_payload(due: &[&Due]) -> PayloadTemplate {
+    let mut due = due.to_vec();
+    due.sort_by(|a, b| a.schedule_id.cmp(&b.schedule_id));
+    let payload = match due.first() {
+        Some(first) if due.iter().all(|d| d.payload == first.payload) => first.payload.clone(),
+        _ => due.iter().map(|d| &d.payload).find(|p| p.is_alert()).cloned().unwrap_or_default()
     };
-    let next_fire_time = fire_time + 1;
-    let sns_client = sns::Client::new(&config);
-    let table_name = env::var(TABLE_NAME)?;
-    let push_table_tame = env::var(PUSH_TABLE_NAME)?;
-    let partition_id = env::var(PARTITION_ID)?;
-    let mut results = ddb_client.query()
-        .table_name(table_name.to_owned())
-        .index_name("next_fire-index")
-        .key_condition_expression("#part = :part_val AND #next_fire < :next_fire_val")
-        .expression_attribute_names("#part", "part")
-        .expression_attribute_names("#next_fire", "next_fire")
-        .expression_attribute_values(":part_val", AttributeValue::S(partition_id.to_owned()))
-        .expression_attribute_values(":next_fire_val", AttributeValue::N(next_fire_time.to_string()))
-        .into_paginator()
-        .send();
-    while let Some(res) = results.next().await {
-        match res?.items() {
-            Some(i) => for item in i {
-                if let Some(AttributeValue::S(id)) = item.get("id") {
-                    debug!("looking at id={}", id);
-                    let push = ddb_client.get_item()
-                        .table_name(push_table_tame.to_string())
-                        .key("id".to_string(), AttributeValue::S(id.to_owned()))
-                        .send()
-                        .await?;
-                    if let Some(item) = push.item() {
-                        if let Some(AttributeValue::S(arn)) = item.get("endpoint_arn") {
-                            let publish_result = sns_client.publish()
-                                .target_arn(arn)
-                                .message("{\"APNS\":{\"aps\":{\"content-available\":1}}}")
-                                .message_attributes(
-                                    "AWS.SNS.MOBILE.APNS.PUSH_TYPE".to_string(),
-                                    MessageAttributeValue::builder()
-                                        .data_type("String")
-                                        .string_value("background")
-                                        .build()
-                                )
-                                .message_attributes(
-                                    "AWS.SNS.MOBILE.APNS.PRIORITY".to_string(),
-                                    MessageAttributeValue::builder()
-                                        .data_type("String")
-                                        .string_value("5")
-                                        .build()
-                                )
+    let schedule_ids: Vec<&str> = due.iter().map(|d| d.schedule_id.as_str()).collect();
+    with_schedule_ids(payload, &schedule_ids)
+}
+
+/// Whether a claim was canceled because the schedule's bucket was claimed
+/// already: its `next_fire` moved on, or its fired record is there. Any other
+/// cancellation, e.g. a conflicting transaction or throttling, wrote nothing.
+fn claimed_elsewhere(canceled: &TransactionCanceledException) -> bool {
+    canceled.cancellation_reasons().unwrap_or_default().iter()
+        .any(|reason| reason.code() == Some("ConditionalCheckFailed"))
+}
+
+/// The result of trying to claim a due schedule.
+enum Claim {
+    /// Claimed; fire it as described.
+    Fire(CatchUp),
+    /// Not claimed, so nothing is sent.
+    Skip(Outcome)
+}
+
+struct Notifier<'a> {
+    ddb_client: ddb::Client,
+    sns_client: sns::Client,
+    web_push_sender: Option<WebPushSender>,
+    table_name: String,
+    push_table_name: String,
+    fired_table_name: String,
+    dead_letters: DeadLetters,
+    rate_limiter: Option<RateLimiter>,
+    /// Claimed schedules by entitlement, delivered once every page's been seen.
+    due: Mutex<HashMap<String, DueDevice>>,
+    /// Over-limit pushes by entitlement, sent once every schedule's been seen.
+    coalesced: Mutex<HashMap<String, Coalesced>>,
+    partition_id: String,
+    shards: Shards,
+    scheduler: Scheduler,
+    push_scheduler: Option<&'a dyn PushScheduler>,
+    tick: Tick,
+    jitter: Jitter,
+    /// The start of the tick being fired, in epoch seconds.
+    fire_time: u64
+}
+
+impl<'a> Notifier<'a> {
+    async fn from_env(push_scheduler: Option<&'a dyn PushScheduler>) -> Result<Notifier<'a>, Error> {
+        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+        let config = aws_config::from_env().region(region_provider).load().await;
+        let tick = Tick::from_env()?;
+        let fire_time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(n) => tick.start(n.as_secs()),
+            Err(_) => 0
+        };
+        let ddb_client = ddb_client(&config);
+        let scheduler = Scheduler::from_env()?;
+        Ok(Notifier {
+            ddb_client: ddb_client.clone(),
+            sns_client: sns::Client::new(&config),
+            web_push_sender: VapidKey::from_env()?.map(|vapid| WebPushSender::new(vapid, Duration::from_secs(tick.seconds()))),
+            table_name: env::var(TABLE_NAME)?,
+            push_table_name: env::var(PUSH_TABLE_NAME)?,
+            fired_table_name: env::var(FIRED_TABLE_NAME)?,
+            dead_letters: DeadLetters::new(ddb_client.clone(), env::var(DEAD_LETTER_TABLE_NAME)?),
+            rate_limiter: RateLimiter::from_env(ddb_client),
+            due: Mutex::new(HashMap::new()),
+            coalesced: Mutex::new(HashMap::new()),
+            partition_id: env::var(PARTITION_ID)?,
+            shards: Shards::from_env()?,
+            scheduler,
+            push_scheduler,
+            tick,
+            jitter: Jitter::from_env()?,
+            fire_time
+        })
+    }
+
+    /// Looks up the push rows for a page of schedules, keyed by entitlement.
+    async fn fetch_pushes(&self, items: &[Item]) -> Result<HashMap<String, Item>, Error> {
+        let entitlements: HashSet<&String> = items.iter()
+            .filter_map(|item| match item.get("entitlement") {
+                Some(AttributeValue::S(entitlement)) => Some(entitlement),
+                _ => None
+            })
+            .collect();
+        let keys: Vec<Item> = entitlements.into_iter()
+            .map(|entitlement| HashMap::from([("id".to_string(), AttributeValue::S(entitlement.to_string()))]))
+            .collect();
+        let mut pushes = HashMap::new();
+        for item in self.batch_get(&self.push_table_name, keys).await? {
+            if let Some(AttributeValue::S(id)) = item.get("id") {
+                pushes.insert(id.to_string(), item);
+            }
+        }
+        Ok(pushes)
+    }
+
+    /// The last tick whose due queue buckets were all read, if it's known.
+    async fn swept(&self) -> Result<Option<u64>, Error> {
+        let queue_table = match self.scheduler.queue_table() {
+            Some(queue_table) => queue_table,
+            None => return Ok(None)
+        };
+        let output = self.ddb_client.get_item()
+            .table_name(queue_table.to_owned())
+            .key("bucket", AttributeValue::S(swept_key(&self.partition_id)))
+            .key("id", AttributeValue::S(SWEPT_ID.to_string()))
+            .consistent_read(true)
+            .send()
+            .await?;
+        match output.item().and_then(|item| item.get("tick")) {
+            Some(AttributeValue::N(tick)) => Ok(Some(u64::from_str(tick)?)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Records that every due queue bucket up to this tick's has been read.
+    /// It never moves back, should an earlier tick finish later.
+    async fn mark_swept(&self) -> Result<(), Error> {
+        let queue_table = match self.scheduler.queue_table() {
+            Some(queue_table) => queue_table,
+            None => return Ok(())
+        };
+        let result = self.ddb_client.update_item()
+            .table_name(queue_table.to_owned())
+            .key("bucket", AttributeValue::S(swept_key(&self.partition_id)))
+            .key("id", AttributeValue::S(SWEPT_ID.to_string()))
+            .update_expression("SET #tick = :tick")
+            .condition_expression("attribute_not_exists(#tick) OR #tick < :tick")
+            .expression_attribute_names("#tick", "tick")
+            .expression_attribute_values(":tick", AttributeValue::N(self.fire_time.to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_conditional_check_failed_exception() {
+                    Ok(())
+                } else {
+                    Err(Error::from(e))
+                }
+            }
+        }
+    }
+
+    /// Reads the schedules a page of due queue entries are for. Entries for
+    /// schedules that have since been removed, or whose `next_fire` has moved
+    /// on, are stale and left out. Returns the schedules and how many were
+    /// stale.
+    async fn dequeue(&self, entries: &[Item]) -> Result<(Vec<Item>, usize), Error> {
+        let mut queued = HashMap::new();
+        for entry in entries {
+            if let (Some(AttributeValue::S(id)), Some(next_fire)) = (entry.get("id"), entry.get("next_fire")) {
+                queued.insert(id.to_string(), next_fire.clone());
+            }
+        }
+        let keys: Vec<Item> = queued.keys()
+            .map(|id| HashMap::from([
+                ("part".to_string(), AttributeValue::S(self.partition_id.to_owned())),
+                ("id".to_string(), AttributeValue::S(id.to_owned()))
+            ]))
+            .collect();
+        let schedules: Vec<Item> = self.batch_get(&self.table_name, keys).await?.into_iter()
+            .filter(|item| match item.get("id") {
+                Some(AttributeValue::S(id)) => queued.get(id) == item.get("next_fire"),
+                _ => false
+            })
+            .collect();
+        let stale = entries.len() - schedules.len();
+        Ok((schedules, stale))
+    }
+
+    /// Gets every item in `keys` from `table_name`, retrying any that
+    /// `BatchGetItem` leaves unprocessed.
+    async fn batch_get(&self, table_name: &str, keys: Vec<Item>) -> Result<Vec<Item>, Error> {
+        let mut found = Vec::new();
+        for chunk in keys.chunks(BATCH_GET_LIMIT) {
+            let mut pending = chunk.to_vec();
+            while !pending.is_empty() {
+                let result = self.ddb_client.batch_get_item()
+                    .request_items(
+                        table_name.to_owned(),
+                        KeysAndAttributes::builder().set_keys(Some(pending)).build()
+                    )
+                    .send()
+                    .await?;
+                if let Some(items) = result.responses().and_then(|r| r.get(table_name)) {
+                    found.extend(items.iter().cloned());
+                }
+                pending = result.unprocessed_keys()
+                    .and_then(|u| u.get(table_name))
+                    .and_then(|k| k.keys())
+                    .map(|k| k.to_vec())
+                    .unwrap_or_default();
+                if !pending.is_empty() {
+                    debug!("{} lookups in {} unprocessed, retrying", pending.len(), table_name);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// The due queue entry for schedule row `id`, due at `next_fire`: in its
+    /// shard's bucket for the first tick that hasn't been read yet and isn't
+    /// before `next_fire`.
+    fn queue_entry(&self, id: &str, next_fire: u64) -> Item {
+        let bucket = self.tick.queue_bucket(next_fire, self.fire_time);
+        HashMap::from([
+            ("bucket".to_string(), AttributeValue::S(queue_key(&self.shards.key(&self.partition_id, id), bucket))),
+            ("id".to_string(), AttributeValue::S(id.to_owned())),
+            ("next_fire".to_string(), AttributeValue::N(next_fire.to_string())),
+            ("expires".to_string(), AttributeValue::N((bucket + QUEUE_RETENTION_SECONDS).to_string()))
+        ])
+    }
+
+    /// Queues a due schedule that this tick didn't get to for the next one,
+    /// since nothing reads this tick's bucket again.
+    async fn requeue(&self, item: &Item) -> Result<(), Error> {
+        let queue_table = match self.scheduler.queue_table() {
+            Some(queue_table) => queue_table,
+            None => return Ok(())
+        };
+        if let (Some(AttributeValue::S(id)), Some(AttributeValue::N(next_fire))) = (item.get("id"), item.get("next_fire")) {
+            let next_fire = u64::from_str(next_fire)?;
+            self.ddb_client.put_item()
+                .table_name(queue_table.to_owned())
+                .set_item(Some(self.queue_entry(id, next_fire)))
+                .send()
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn notify(&self, item: &Item, pushes: &HashMap<String, Item>, deadline: Option<SystemTime>) -> Result<Outcome, Error> {
+        if near_deadline(deadline) {
+            self.requeue(item).await?;
+            return Ok(Outcome::Deferred)
+        }
+        // Claim the bucket before sending, so a crash or a concurrent
+        // invocation can at worst drop this push, never send it twice.
+        let catch_up = match self.claim(item).await? {
+            Claim::Fire(catch_up) => catch_up,
+            Claim::Skip(outcome) => return Ok(outcome)
+        };
+        if let (Some(AttributeValue::S(id)), Some(AttributeValue::S(entitlement))) = (item.get("id"), item.get("entitlement")) {
+            debug!("looking at id={}", id);
+            if catch_up.missed > 0 {
+                warn!("{} missed {} occurrences", id, catch_up.missed);
+            }
+            match pushes.get(entitlement) {
+                Some(push) if catch_up.fires > 0 => {
+                    let due = Due { schedule_id: id.to_string(), payload: decode_payload(item), fires: catch_up.fires };
+                    self.due.lock().unwrap().entry(entitlement.to_string())
+                        .or_insert_with(|| DueDevice { push: push.clone(), due: Vec::new() })
+                        .due.push(due);
+                },
+                Some(_) => (),
+                None => warn!("no push entry for id: {}", id)
+            }
+        } else {
+            warn!("item with no id or entitlement: {:?}", item);
+        }
+        Ok(Outcome::Processed)
+    }
+
+    /// Sends each device one push for all of its schedules that came due,
+    /// or one per occurrence when a catch-up policy fires a schedule more than
+    /// once. Returns how many pushes were sent.
+    async fn deliver_due(&self, parallelism: usize, deadline: Option<SystemTime>) -> Result<u64, Error> {
+        let devices: Vec<(String, DueDevice)> = self.due.lock().unwrap().drain().collect();
+        let results: Vec<Result<u64, Error>> = futures::stream::iter(devices)
+            .map(|(entitlement, device)| self.deliver_device(entitlement, device, deadline))
+            .buffer_unordered(parallelism)
+            .collect()
+            .await;
+        results.into_iter().sum()
+    }
+
+    async fn deliver_device(&self, entitlement: String, device: DueDevice, deadline: Option<SystemTime>) -> Result<u64, Error> {
+        let occurrences = device.due.iter().map(|d| d.fires).max().unwrap_or(0);
+        let mut sent = 0;
+        for occurrence in 0..occurrences {
+            let due: Vec<&Due> = device.due.iter().filter(|d| d.fires > occurrence).collect();
+            let ids = due.iter().map(|d| d.schedule_id.as_str()).collect::<Vec<&str>>().join(",");
+            let payload = grouped_payload(&due);
+            // Already claimed, so a push that isn't sent now is dead-lettered.
+            let error = if near_deadline(deadline) {
+                Some(("deadline reached before sending".to_string(), 0))
+            } else if !self.admit(&entitlement).await {
+                debug!("{} is over the rate limit, coalescing {}", entitlement, ids);
+                for d in &due {
+                    self.coalesce(&entitlement, &d.schedule_id, &device.push);
+                }
+                continue
+            } else {
+                self.deliver(&ids, &entitlement, &payload, &device.push).await.err().map(|(e, attempts)| (e.reason, attempts))
+            };
+            match error {
+                Some((error, attempts)) => self.dead_letters.put(&DeadLetter {
+                    id: format!("{}#{}#{}#{:016x}", entitlement, self.fire_time, occurrence, stable_hash(ids.as_bytes())),
+                    schedule_id: ids,
+                    entitlement: entitlement.to_string(),
+                    payload,
+                    error,
+                    attempts
+                }).await?,
+                None => sent += 1
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Whether the rate limits leave room for another push to `entitlement`.
+    async fn admit(&self, entitlement: &str) -> bool {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.acquire(entitlement).await,
+            None => true
+        }
+    }
+
+    fn coalesce(&self, entitlement: &str, id: &str, push: &Item) {
+        let mut coalesced = self.coalesced.lock().unwrap();
+        let entry = coalesced.entry(entitlement.to_string())
+            .or_insert_with(|| Coalesced { push: push.clone(), schedule_ids: Vec::new() });
+        if !entry.schedule_ids.iter().any(|s| s == id) {
+            entry.schedule_ids.push(id.to_string());
+        }
+    }
+
+    /// Sends one push to each principal that went over its limit, in place of
+    /// the pushes that weren't sent. These don't take from the principal's
+    /// bucket again, but do need room in the global one; any that can't be
+    /// sent are dead-lettered rather than dropped.
+    async fn deliver_coalesced(&self, deadline: Option<SystemTime>) -> Result<usize, Error> {
+        let coalesced: Vec<(String, Coalesced)> = self.coalesced.lock().unwrap().drain().collect();
+        let count = coalesced.len();
+        for (entitlement, Coalesced { push, schedule_ids }) in coalesced {
+            let payload = coalesced_payload(&schedule_ids);
+            let ids = schedule_ids.join(",");
+            let global_room = match &self.rate_limiter {
+                Some(limiter) => !near_deadline(deadline) && limiter.acquire_global().await,
+                None => true
+            };
+            let error = if near_deadline(deadline) {
+                Some(("deadline reached before sending".to_string(), 0))
+            } else if !global_room {
+                Some(("over the global rate limit".to_string(), 0))
+            } else {
+                self.deliver(&ids, &entitlement, &payload, &push).await.err().map(|(e, attempts)| (e.reason, attempts))
+            };
+            if let Some((error, attempts)) = error {
+                self.dead_letters.put(&DeadLetter {
+                    id: format!("{}#{}#coalesced", entitlement, self.fire_time),
+                    schedule_id: ids,
+                    entitlement,
+                    payload,
+                    error,
+                    attempts
+                }).await?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Delivers one push, retrying transient failures with jittered
+    /// exponential backoff. Returns the last failure and how many attempts
+    /// were made if it couldn't be delivered.
+    async fn deliver(&self, id: &str, entitlement: &str, payload: &PayloadTemplate, push: &Item) -> Result<(), (PublishError, u32)> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.publish(entitlement, payload, push).await {
+                Ok(()) => {
+                    info!("send push for id: {}", id);
+                    self.record_delivery(id).await;
+                    return Ok(())
+                },
+                Err(e) if e.transient && attempts < MAX_PUBLISH_ATTEMPTS => {
+                    let backoff = backoff(attempts);
+                    warn!("error publishing for id {} (attempt {}), retrying in {:?}: {}", id, attempts, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                },
+                Err(e) => {
+                    error!("error publishing for id {} after {} attempts: {}", id, attempts, e);
+                    return Err((e, attempts))
+                }
+            }
+        }
+    }
+
+    /// Makes a single attempt at publishing `payload` to the device in `push`.
+    async fn publish(&self, entitlement: &str, payload: &PayloadTemplate, push: &Item) -> Result<(), PublishError> {
+        if push.get("device_type") == Some(&AttributeValue::S("web_push".to_string())) {
+            match (&self.web_push_sender, WebPushSubscription::from_item(push)) {
+                (Some(sender), Some(subscription)) => {
+                    match sender.send(&subscription, payload.web_push().to_string().as_bytes()).await {
+                        Ok(Delivery::Sent) => Ok(()),
+                        Ok(Delivery::Gone) => {
+                            warn!("subscription gone for {}, removing push entry", entitlement);
+                            self.ddb_client.delete_item()
+                                .table_name(self.push_table_name.to_string())
+                                .key("id", AttributeValue::S(entitlement.to_owned()))
                                 .send()
-                                .await;
-                            match publish_result {
-                                Err(e) => error!("error publishing to {}: {}", arn, e),
-                                Ok(_) => info!("send push for id: {}", id)
-                            }
-                        } else {
-                            warn!("no endpoint_arn for push item: {:?}", item);
-                        }
-                    } else {
-                        warn!("no push entry for id: {}", id);
+                                .await
+                                .map_err(|e| PublishError::transient(e.to_string()))?;
+                            Ok(())
+                        },
+                        Err(e) => Err(PublishError { reason: e.reason, transient: e.transient })
                     }
-                } else {
-                    warn!("item with no id: {:?}", item);
+                },
+                (None, _) => Err(PublishError::permanent("web push is not configured".to_string())),
+                (_, None) => Err(PublishError::permanent(format!("invalid web push subscription: {:?}", push)))
+            }
+        } else if let Some(AttributeValue::S(arn)) = push.get("endpoint_arn") {
+            let message = payload.sns_message(&[decode_platform(push)])
+                .map_err(|e| PublishError::permanent(format!("can't render payload: {}", e)))?;
+            let (push_type, priority) = payload.apns_push_type();
+            self.sns_client.publish()
+                .target_arn(arn)
+                .message_structure("json")
+                .message(message)
+                .message_attributes(
+                    "AWS.SNS.MOBILE.APNS.PUSH_TYPE".to_string(),
+                    MessageAttributeValue::builder()
+                        .data_type("String")
+                        .string_value(push_type)
+                        .build()
+                )
+                .message_attributes(
+                    "AWS.SNS.MOBILE.APNS.PRIORITY".to_string(),
+                    MessageAttributeValue::builder()
+                        .data_type("String")
+                        .string_value(priority)
+                        .build()
+                )
+                .send()
+                .await
+                .map_err(|e| PublishError { transient: is_transient(&e), reason: format!("{} ({})", e, arn) })?;
+            Ok(())
+        } else {
+            Err(PublishError::permanent(format!("no endpoint_arn for push item: {:?}", push)))
+        }
+    }
+
+    /// Stamps `last_delivered` on the schedules a push was for, given as a
+    /// comma separated list of IDs. The push has gone by now, so failing to
+    /// record it is only logged, and schedules removed since aren't brought
+    /// back.
+    async fn record_delivery(&self, ids: &str) {
+        let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(n) => n.as_secs(),
+            Err(_) => return
+        };
+        for id in ids.split(',').filter(|id| !id.is_empty()) {
+            let result = self.ddb_client.update_item()
+                .table_name(self.table_name.to_owned())
+                .key("part", AttributeValue::S(self.partition_id.to_owned()))
+                .key("id", AttributeValue::S(id.to_owned()))
+                .update_expression("SET #delivered = :now")
+                .condition_expression("attribute_exists(#id)")
+                .expression_attribute_names("#delivered", "last_delivered")
+                .expression_attribute_names("#id", "id")
+                .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+                .send()
+                .await;
+            if let Err(e) = result {
+                let e = e.into_service_error();
+                if !e.is_conditional_check_failed_exception() {
+                    warn!("couldn't record delivery for {}: {}", id, e);
                 }
-                if let Some(v) = item.get("fire_interval") {
-                    match v {
-                        AttributeValue::N(interval) => {
-                            if let Some(id) = item.get("id") {
-                                match id {
-                                    AttributeValue::S(idval) => {
-                                        match u128::from_str(interval.as_str()) {
-                                            Ok(i) => {
-                                                let next = fire_time + i;
-                                                ddb_client.update_item()
-                                                    .table_name(table_name.to_owned())
-                                                    .key("part", AttributeValue::S(partition_id.to_owned()))
-                                                    .key("id", AttributeValue::S(idval.to_string()))
-                                                    .update_expression("SET #fire = :i")
-                                                    .expression_attribute_names("#fire", "next_fire")
-                                                    .expression_attribute_values(":i", AttributeValue::N(next.to_string()))
-                                                    .send()
-                                                    .await?;
-                                            },
-                                            Err(_) => error!("couldn't parse number: {:#?}", interval)
-                                        }
-                                    },
-                                    _ => error!("invalid ID in item {:#?}", item)
-                                }
-                            } else {
-                                error!("no id for item: {:#?}", item)
-                            }
-                        }
-                        _ => error!("ignoring non-number fire_interval {:#?}", v)
+            }
+        }
+    }
+
+    /// Gives a schedule that's scheduled one occurrence at a time its
+    /// EventBridge schedule for `next_fire`, or removes it once there's none.
+    /// Rate schedules carry on by themselves.
+    async fn reschedule(&self, id: &str, item: &Item, next_fire: Option<u64>) -> Result<(), Error> {
+        let push_scheduler = match self.push_scheduler {
+            Some(push_scheduler) => push_scheduler,
+            None => return Ok(())
+        };
+        match next_fire.map(|next_fire| schedule_item::push_timing(item, next_fire)) {
+            Some(timing @ Timing::At(_)) => push_scheduler.put(&PushSchedule::new(&self.partition_id, id, timing)).await?,
+            Some(Timing::Rate { .. }) => (),
+            None => push_scheduler.delete(&schedule_name(&self.partition_id, id)).await?
+        }
+        Ok(())
+    }
+
+    /// Advances the schedule's `next_fire` past the bucket it was read at, and
+    /// writes the idempotency record for that (schedule, bucket), in one
+    /// transaction, along with its next due queue entry if schedules are
+    /// queued. Occurrences the catch-up policy drops are added to the
+    /// schedule's `missed_fires`.
+    async fn claim(&self, item: &Item) -> Result<Claim, Error> {
+        let offset = match item.get("id") {
+            Some(AttributeValue::S(id)) => self.jitter.offset(id),
+            _ => 0
+        };
+        let (id, expected, recurrence) = match (item.get("id"), item.get("next_fire"), decode_recurrence(item, offset)) {
+            (Some(AttributeValue::S(id)), Some(AttributeValue::N(expected)), Some(recurrence)) => {
+                match u64::from_str(expected.as_str()) {
+                    Ok(expected) => (id, expected, recurrence),
+                    Err(_) => {
+                        error!("couldn't parse next_fire: {:#?}", expected);
+                        return Ok(Claim::Skip(Outcome::Invalid))
                     }
                 }
             },
-            None => break
+            _ => {
+                error!("schedule missing id, next_fire, or a valid fire_interval or cron: {:#?}", item);
+                return Ok(Claim::Skip(Outcome::Invalid))
+            }
+        };
+        if is_legacy_bucket(expected) {
+            error!("{} still has a 5 minute bucket next_fire, run selektor-admin migrate: {}", id, expected);
+            return Ok(Claim::Skip(Outcome::Invalid))
+        }
+        let policy = decode_catch_up(item);
+        let deferred_from = match item.get("deferred_from") {
+            Some(AttributeValue::N(deferred_from)) => u64::from_str(deferred_from).ok(),
+            _ => None
+        };
+        let mut catch_up = match deferred_from {
+            Some(deferred_from) => policy.apply_held(deferred_from, &recurrence, self.fire_time, self.tick),
+            None => policy.apply(expected, &recurrence, self.fire_time, self.tick)
+        };
+        // A fire in quiet hours is put off until they end, plus the jitter so
+        // that they don't all go at once, instead of sent. Only this push
+        // moves; the occurrence it's for is kept to carry on from.
+        let mut deferred = None;
+        if catch_up.fires > 0 {
+            if let Some(end) = quiet_until(self.fire_time, decode_time_zone(item), &decode_quiet_hours(item)) {
+                debug!("{} is in quiet hours, deferring to {}", id, end + offset);
+                deferred = Some(deferred_from.unwrap_or(expected));
+                catch_up = CatchUp { fires: 0, missed: 0, next_fire: Some(end + offset) };
+            }
         }
+        let expires = (SystemTime::now() + FIRED_RECORD_TTL).duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let mut advance = Update::builder()
+            .table_name(self.table_name.to_owned())
+            .key("part", AttributeValue::S(self.partition_id.to_owned()))
+            .key("id", AttributeValue::S(id.to_string()))
+            .condition_expression("#fire = :expected")
+            .expression_attribute_names("#fire", "next_fire")
+            .expression_attribute_names("#deferred", "deferred_from")
+            .expression_attribute_names("#missed", "missed_fires")
+            .expression_attribute_values(":missed", AttributeValue::N(catch_up.missed.to_string()))
+            .expression_attribute_values(":expected", AttributeValue::N(expected.to_string()));
+        let mut sets = Vec::new();
+        let mut removes = Vec::new();
+        match catch_up.next_fire {
+            Some(next_fire) => {
+                sets.push("#fire = :i");
+                advance = advance.expression_attribute_values(":i", AttributeValue::N(next_fire.to_string()));
+            },
+            // A cron schedule with no more occurrences drops out of the index.
+            None => removes.push("#fire")
+        }
+        match deferred {
+            Some(deferred) => {
+                sets.push("#deferred = :deferred");
+                advance = advance.expression_attribute_values(":deferred", AttributeValue::N(deferred.to_string()));
+            },
+            None => removes.push("#deferred")
+        }
+        let mut expression = Vec::new();
+        if !sets.is_empty() {
+            expression.push(format!("SET {}", sets.join(", ")));
+        }
+        if !removes.is_empty() {
+            expression.push(format!("REMOVE {}", removes.join(", ")));
+        }
+        expression.push("ADD #missed :missed".to_string());
+        advance = advance.update_expression(expression.join(" "));
+        let mut transaction = self.ddb_client.transact_write_items()
+            .transact_items(TransactWriteItem::builder()
+                .update(advance.build())
+                .build())
+            .transact_items(TransactWriteItem::builder()
+                .put(Put::builder()
+                    .table_name(self.fired_table_name.to_owned())
+                    .item("id", AttributeValue::S(format!("{}#{}", id, expected)))
+                    .item("fired_at", AttributeValue::N(self.fire_time.to_string()))
+                    .item("expires", AttributeValue::N(expires.to_string()))
+                    .condition_expression("attribute_not_exists(#id)")
+                    .expression_attribute_names("#id", "id")
+                    .build())
+                .build());
+        if let (Some(queue_table), Some(next_fire)) = (self.scheduler.queue_table(), catch_up.next_fire) {
+            transaction = transaction.transact_items(TransactWriteItem::builder()
+                .put(Put::builder()
+                    .table_name(queue_table.to_owned())
+                    .set_item(Some(self.queue_entry(id, next_fire)))
+                    .build())
+                .build());
+        }
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let e = match transaction.clone().send().await {
+                Ok(_) => break,
+                Err(e) => e.into_service_error()
+            };
+            match &e.kind {
+                TransactWriteItemsErrorKind::TransactionCanceledException(canceled) if claimed_elsewhere(canceled) => {
+                    debug!("bucket {} of {} already claimed: {}", expected, id, e);
+                    return Ok(Claim::Skip(Outcome::AlreadyClaimed))
+                },
+                // A conflicting transaction, or throttling: nothing was
+                // written, so it's still due.
+                TransactWriteItemsErrorKind::TransactionCanceledException(_) if attempts < MAX_CLAIM_ATTEMPTS => {
+                    let backoff = backoff(attempts);
+                    warn!("claiming {} was canceled (attempt {}), retrying in {:?}: {}", id, attempts, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                },
+                TransactWriteItemsErrorKind::TransactionCanceledException(_) => {
+                    warn!("couldn't claim {}, leaving it for the next tick: {}", id, e);
+                    self.requeue(item).await?;
+                    return Ok(Claim::Skip(Outcome::Deferred))
+                },
+                _ => return Err(Error::from(e))
+            }
+        }
+        // It's claimed now, so it's sent either way; `deliver` tries again,
+        // and a tick would find it when it's due.
+        if let Err(e) = self.reschedule(id, item, catch_up.next_fire).await {
+            error!("couldn't reschedule {}: {}", id, e);
+        }
+        Ok(Claim::Fire(catch_up))
     }
+}
+
+/// Fails unless the tables `run_notify` uses match `selektor_common::schema`.
+pub async fn check_schema() -> Result<(), Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    let ddb_client = ddb_client(&config);
+    let mut tables = vec![
+        (TABLE_NAME, schema::SCHEDULES),
+        (PUSH_TABLE_NAME, schema::PUSH),
+        (FIRED_TABLE_NAME, schema::FIRED),
+        (DEAD_LETTER_TABLE_NAME, schema::DEAD_LETTERS)
+    ];
+    if env::var(RATE_LIMIT_TABLE_NAME).is_ok() {
+        tables.push((RATE_LIMIT_TABLE_NAME, schema::RATE_LIMITS));
+    }
+    if Scheduler::from_env()?.queue_table().is_some() {
+        tables.push((DUE_QUEUE_TABLE_NAME, schema::DUE_QUEUE));
+    }
+    let mut named = Vec::new();
+    for (var, table) in tables {
+        named.push((env::var(var)?, table));
+    }
+    check_tables(&ddb_client, &named).await
+}
+
+/// The push scheduler for the deployment's `SCHEDULER`, if it uses one.
+pub async fn push_scheduler() -> Result<Option<Box<dyn PushScheduler>>, Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    Ok(push_scheduler::for_scheduler(&Scheduler::from_env()?, &config)?)
+}
+
+/// Fires the schedules due in this tick. With `SCHEDULER=eventbridge` it
+/// picks up anything `push_scheduler`'s schedules missed, and sets their
+/// schedules right.
+pub async fn function_handler(event: LambdaEvent<CloudWatchEvent>, push_scheduler: Option<&dyn PushScheduler>) -> Result<(), Error> {
+    let deadline = match event.context.deadline {
+        0 => None,
+        millis => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    };
+    let parallelism = env::var(NOTIFY_PARALLELISM).ok()
+        .and_then(|p| usize::from_str(&p).ok())
+        .unwrap_or(DEFAULT_PARALLELISM)
+        .max(1);
+    let notifier = Notifier::from_env(push_scheduler).await?;
+    let next_fire_time = notifier.tick.next(notifier.fire_time);
+    // A queue's buckets for ticks that didn't run, or ran out of time, are
+    // read along with this tick's.
+    let ticks = match notifier.scheduler.queue_table() {
+        Some(_) => notifier.tick.unread(notifier.swept().await?, notifier.fire_time),
+        None => Vec::new()
+    };
+    // Every shard is queried at once, and pages handled as they come in.
+    let queries = notifier.shards.keys(&notifier.partition_id).into_iter().flat_map(|shard| match &notifier.scheduler {
+        Scheduler::Index | Scheduler::EventBridge => vec![notifier.ddb_client.query()
+            .table_name(notifier.table_name.to_owned())
+            .index_name(schema::DUE_INDEX.name)
+            .key_condition_expression("#shard = :shard AND #next_fire < :next_fire_val")
+            .expression_attribute_names("#shard", "shard")
+            .expression_attribute_names("#next_fire", "next_fire")
+            .expression_attribute_values(":shard", AttributeValue::S(shard))
+            .expression_attribute_values(":next_fire_val", AttributeValue::N(next_fire_time.to_string()))],
+        Scheduler::Queue { table_name } => ticks.iter().map(|tick| notifier.ddb_client.query()
+            .table_name(table_name.to_owned())
+            .key_condition_expression("#bucket = :bucket")
+            .expression_attribute_names("#bucket", "bucket")
+            .expression_attribute_values(":bucket", AttributeValue::S(queue_key(&shard, *tick))))
+            .collect()
+    });
+    let mut complete = true;
+    let mut results = futures::stream::select_all(queries.map(|query| Box::pin(query.into_paginator().send())));
+    let mut processed = 0;
+    let mut claimed = 0;
+    let mut invalid = 0;
+    let mut deferred = 0;
+    let mut stale = 0;
+    while let Some(res) = results.next().await {
+        let page = res?;
+        let items = match page.items() {
+            Some(items) => items,
+            None => continue
+        };
+        let items = match notifier.scheduler {
+            Scheduler::Index | Scheduler::EventBridge => items.to_vec(),
+            Scheduler::Queue { .. } => {
+                let (schedules, stale_entries) = notifier.dequeue(items).await?;
+                stale += stale_entries;
+                schedules
+            }
+        };
+        if near_deadline(deadline) {
+            warn!("deadline approaching, leaving remaining schedules for the next tick");
+            for item in &items {
+                notifier.requeue(item).await?;
+            }
+            complete = false;
+            break
+        }
+        let pushes = notifier.fetch_pushes(&items).await?;
+        let outcomes: Vec<Result<Outcome, Error>> = futures::stream::iter(&items)
+            .map(|item| notifier.notify(item, &pushes, deadline))
+            .buffer_unordered(parallelism)
+            .collect()
+            .await;
+        for outcome in outcomes {
+            match outcome? {
+                Outcome::Processed => processed += 1,
+                Outcome::AlreadyClaimed => claimed += 1,
+                Outcome::Invalid => invalid += 1,
+                Outcome::Deferred => deferred += 1
+            }
+        }
+    }
+    if complete && notifier.scheduler.queue_table().is_some() {
+        notifier.mark_swept().await?;
+    }
+    let sent = notifier.deliver_due(parallelism, deadline).await?;
+    let coalesced = notifier.deliver_coalesced(deadline).await?;
+    info!("processed {} schedules, {} already claimed, {} invalid, deferred {}, {} stale queue entries; sent {} pushes, coalesced pushes for {} principals",
+        processed, claimed, invalid, deferred, stale, sent, coalesced);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_near_deadline() {
+    assert!(!near_deadline(None));
+    assert!(near_deadline(Some(SystemTime::now())));
+    assert!(near_deadline(Some(SystemTime::now() + DEADLINE_MARGIN / 2)));
+    assert!(!near_deadline(Some(SystemTime::now() + DEADLINE_MARGIN * 2)));
+}
+
+#[test]
+fn test_coalesced_payload() {
+    let payload = coalesced_payload(&["a".to_string(), "b".to_string()]);
+    assert!(!payload.is_alert());
+    assert_eq!(payload.gcm()["data"], json!({"schedule_ids": "[\"a\",\"b\"]", "content-available": "1"}));
+    assert_eq!(payload.apns()["schedule_ids"], json!(["a", "b"]));
+}
+
+#[test]
+fn test_grouped_payload() {
+    let background = Due { schedule_id: "b".to_string(), payload: PayloadTemplate::default(), fires: 1 };
+    let alert = Due {
+        schedule_id: "c".to_string(),
+        payload: PayloadTemplate { body: Some("changed".to_string()), ..Default::default() },
+        fires: 1
+    };
+    let other_alert = Due {
+        schedule_id: "a".to_string(),
+        payload: PayloadTemplate { body: Some("also changed".to_string()), ..Default::default() },
+        fires: 1
+    };
+    let payload = grouped_payload(&[&background]);
+    assert!(!payload.is_alert());
+    assert_eq!(payload.data["schedule_ids"], json!(["b"]));
+    let payload = grouped_payload(&[&alert, &background]);
+    assert_eq!(payload.body.as_deref(), Some("changed"));
+    assert_eq!(payload.data["schedule_ids"], json!(["b", "c"]));
+    let payload = grouped_payload(&[&alert, &background, &other_alert]);
+    assert_eq!(payload.body.as_deref(), Some("also changed"));
+    assert_eq!(payload.data["schedule_ids"], json!(["a", "b", "c"]));
+    assert!(!payload.data.contains_key("schedule_count"));
+}
+
+#[test]
+fn test_with_schedule_ids() {
+    let schedule_ids: Vec<String> = (0..1000).map(|i| format!("schedule-{:04}", i)).collect();
+    let schedule_ids: Vec<&str> = schedule_ids.iter().map(String::as_str).collect();
+    let template = PayloadTemplate { body: Some("changed".to_string()), ..Default::default() };
+    let payload = with_schedule_ids(template, &schedule_ids);
+    assert!(payload.validate().is_ok());
+    assert_eq!(payload.data["schedule_count"], json!(1000));
+    let sent = payload.data["schedule_ids"].as_array().unwrap();
+    assert!(!sent.is_empty() && sent.len() < 1000);
+    assert_eq!(sent[0], json!("schedule-0000"));
+    // One more wouldn't have fit.
+    let mut more = payload.clone();
+    more.data.insert("schedule_ids".to_string(), json!(&schedule_ids[..sent.len() + 1]));
+    assert!(more.validate().is_err());
+}
+
+#[test]
+fn test_claimed_elsewhere() {
+    use aws_sdk_dynamodb::model::CancellationReason;
+    let canceled = |codes: &[&str]| TransactionCanceledException::builder()
+        .set_cancellation_reasons(Some(codes.iter()
+            .map(|code| CancellationReason::builder().code(*code).build())
+            .collect()))
+        .build();
+    assert!(claimed_elsewhere(&canceled(&["None", "ConditionalCheckFailed"])));
+    assert!(!claimed_elsewhere(&canceled(&["TransactionConflict", "None"])));
+    assert!(!claimed_elsewhere(&canceled(&["ThrottlingError"])));
+    assert!(!claimed_elsewhere(&TransactionCanceledException::builder().build()));
+}
@@ -1,5 +1,3 @@
-mod lib;
-
 use lambda_runtime::{Error, run, service_fn};
 use std::env;
 
@@ -20,5 +18,7 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
-    run(service_fn(lib::function_handler)).await
+    run_notify::check_schema().await?;
+    let push_scheduler = run_notify::push_scheduler().await?;
+    run(service_fn(|event| run_notify::function_handler(event, push_scheduler.as_deref()))).await
 }
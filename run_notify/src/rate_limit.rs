@@ -0,0 +1,259 @@
+use aws_sdk_dynamodb as ddb;
+use aws_sdk_dynamodb::model::AttributeValue;
+use lambda_runtime::Error;
+use std::env;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+pub const RATE_LIMIT_TABLE_NAME: &str = "RATE_LIMIT_TABLE_NAME";
+const PRINCIPAL_PUSHES_PER_HOUR: &str = "PRINCIPAL_PUSHES_PER_HOUR";
+const PRINCIPAL_PUSH_BURST: &str = "PRINCIPAL_PUSH_BURST";
+const GLOBAL_PUSHES_PER_HOUR: &str = "GLOBAL_PUSHES_PER_HOUR";
+const GLOBAL_PUSH_BURST: &str = "GLOBAL_PUSH_BURST";
+
+const DEFAULT_PRINCIPAL_RATE: Rate = Rate { per_hour: 60, burst: 10 };
+const DEFAULT_GLOBAL_RATE: Rate = Rate { per_hour: 360_000, burst: 1_000 };
+/// Bucket rows are kept this long after they'd have refilled, via the table's TTL.
+const BUCKET_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const GLOBAL_KEY: &str = "global";
+
+/// A token bucket that refills at `per_hour` tokens an hour and holds at most
+/// `burst` of them.
+///
+/// Buckets are kept as GCRA state: the "theoretical arrival time" (TAT) at
+/// which the bucket would be full again, in epoch milliseconds. Taking a token
+/// pushes the TAT one refill interval later, which is allowed as long as the
+/// TAT stays within `burst` intervals of now. That makes every take a single
+/// conditional update, with no read beforehand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub per_hour: u64,
+    pub burst: u64
+}
+
+impl Rate {
+    fn from_env(rate_var: &str, burst_var: &str, default: Rate) -> Rate {
+        let parse = |var: &str| env::var(var).ok().and_then(|v| u64::from_str(&v).ok());
+        Rate {
+            per_hour: parse(rate_var).unwrap_or(default.per_hour).max(1),
+            burst: parse(burst_var).unwrap_or(default.burst).max(1)
+        }
+    }
+
+    /// Milliseconds it takes to refill one token.
+    fn interval(&self) -> u64 {
+        (3_600_000 / self.per_hour.max(1)).max(1)
+    }
+
+    /// What taking a token at `now` does to a bucket.
+    fn take(&self, now: u64) -> Take {
+        Take {
+            now,
+            limit: now + self.interval() * (self.burst.max(1) - 1),
+            interval: self.interval()
+        }
+    }
+}
+
+/// Taking a token from a bucket at `now`, as a change to its TAT.
+/// `RateLimiter::take` makes it as conditional updates in DynamoDB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Take {
+    now: u64,
+    /// The latest the TAT may be for a token to still be available.
+    limit: u64,
+    interval: u64
+}
+
+impl Take {
+    /// The TAT after taking a token from a bucket with the given one, or
+    /// `None` if the bucket is empty.
+    fn apply(&self, tat: Option<u64>) -> Option<u64> {
+        match tat {
+            // In use: push it along by one interval if that leaves it within
+            // the burst.
+            Some(tat) if tat >= self.now => if tat <= self.limit { Some(tat + self.interval) } else { None },
+            // New, or idle long enough to be full: start over from now.
+            _ => Some(self.now + self.interval)
+        }
+    }
+
+    /// When the bucket's row can expire, in epoch seconds: once it would
+    /// have refilled, and then some.
+    fn expires(&self) -> u64 {
+        (Duration::from_millis(self.limit + self.interval) + BUCKET_RECORD_TTL).as_secs()
+    }
+}
+
+fn principal_key(entitlement: &str) -> String {
+    format!("principal#{}", entitlement)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64
+}
+
+/// Per-principal and global token buckets, shared across invocations through
+/// the rate-limit table.
+pub struct RateLimiter {
+    ddb_client: ddb::Client,
+    table_name: String,
+    principal: Rate,
+    global: Rate
+}
+
+impl RateLimiter {
+    /// Reads the limits from the environment. Without `RATE_LIMIT_TABLE_NAME`
+    /// pushes aren't limited.
+    pub fn from_env(ddb_client: ddb::Client) -> Option<RateLimiter> {
+        env::var(RATE_LIMIT_TABLE_NAME).ok().map(|table_name| RateLimiter {
+            ddb_client,
+            table_name,
+            principal: Rate::from_env(PRINCIPAL_PUSHES_PER_HOUR, PRINCIPAL_PUSH_BURST, DEFAULT_PRINCIPAL_RATE),
+            global: Rate::from_env(GLOBAL_PUSHES_PER_HOUR, GLOBAL_PUSH_BURST, DEFAULT_GLOBAL_RATE)
+        })
+    }
+
+    /// Takes a token from `entitlement`'s bucket, then from the global one.
+    /// The principal's token is given back when the global bucket is empty,
+    /// so a busy tick doesn't use up a principal's limit on pushes it was
+    /// never sent.
+    pub async fn acquire(&self, entitlement: &str) -> bool {
+        let key = principal_key(entitlement);
+        if !self.take_or_allow(&key, &self.principal).await {
+            return false
+        }
+        if !self.acquire_global().await {
+            self.give_back(&key, &self.principal).await;
+            return false
+        }
+        true
+    }
+
+    pub async fn acquire_global(&self) -> bool {
+        self.take_or_allow(GLOBAL_KEY, &self.global).await
+    }
+
+    /// Undoes taking a token from a bucket that's still in use. Failing to
+    /// only errs on the side of sending less.
+    async fn give_back(&self, key: &str, rate: &Rate) {
+        let result = self.ddb_client.update_item()
+            .table_name(self.table_name.to_owned())
+            .key("id", AttributeValue::S(key.to_string()))
+            .update_expression("SET #tat = #tat - :interval")
+            .condition_expression("attribute_exists(#tat)")
+            .expression_attribute_names("#tat", "tat")
+            .expression_attribute_values(":interval", AttributeValue::N(rate.interval().to_string()))
+            .send()
+            .await;
+        if let Err(e) = result {
+            warn!("couldn't give back a token to {}: {}", key, e);
+        }
+    }
+
+    /// A limiter that can't be reached doesn't hold pushes back.
+    async fn take_or_allow(&self, key: &str, rate: &Rate) -> bool {
+        match self.take(key, rate, now_millis()).await {
+            Ok(taken) => taken,
+            Err(e) => {
+                warn!("couldn't check rate limit for {}, allowing: {}", key, e);
+                true
+            }
+        }
+    }
+
+    async fn take(&self, key: &str, rate: &Rate, now: u64) -> Result<bool, Error> {
+        let take = rate.take(now);
+        let expires = take.expires();
+        // The first arm of `Take::apply`: while a bucket is in use its TAT is
+        // ahead of now; push it along by one interval if that leaves it within
+        // the burst.
+        let result = self.ddb_client.update_item()
+            .table_name(self.table_name.to_owned())
+            .key("id", AttributeValue::S(key.to_string()))
+            .update_expression("SET #tat = #tat + :interval, #expires = :expires")
+            .condition_expression("#tat BETWEEN :now AND :limit")
+            .expression_attribute_names("#tat", "tat")
+            .expression_attribute_names("#expires", "expires")
+            .expression_attribute_values(":interval", AttributeValue::N(take.interval.to_string()))
+            .expression_attribute_values(":expires", AttributeValue::N(expires.to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(take.now.to_string()))
+            .expression_attribute_values(":limit", AttributeValue::N(take.limit.to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => return Ok(true),
+            Err(e) => {
+                let e = e.into_service_error();
+                if !e.is_conditional_check_failed_exception() {
+                    return Err(Error::from(e))
+                }
+            }
+        }
+        // Otherwise the bucket is either full (a new or idle one), or empty.
+        // A full one starts over from now, as in the second arm. Should another invocation take
+        // from it in between, this is refused even if a token was left.
+        let result = self.ddb_client.update_item()
+            .table_name(self.table_name.to_owned())
+            .key("id", AttributeValue::S(key.to_string()))
+            .update_expression("SET #tat = :tat, #expires = :expires")
+            .condition_expression("attribute_not_exists(#tat) OR #tat < :now")
+            .expression_attribute_names("#tat", "tat")
+            .expression_attribute_names("#expires", "expires")
+            .expression_attribute_values(":tat", AttributeValue::N(take.apply(None).unwrap_or_default().to_string()))
+            .expression_attribute_values(":expires", AttributeValue::N(expires.to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(take.now.to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_conditional_check_failed_exception() {
+                    debug!("{} is over its rate limit", key);
+                    Ok(false)
+                } else {
+                    Err(Error::from(e))
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    // Six an hour, so one token every ten minutes, and up to three at once.
+    let rate = Rate { per_hour: 6, burst: 3 };
+    let minute = 60_000;
+    assert_eq!(rate.take(0), Take { now: 0, limit: 20 * minute, interval: 10 * minute });
+    let mut tat = None;
+    for _ in 0..3 {
+        tat = rate.take(0).apply(tat);
+        assert!(tat.is_some());
+    }
+    assert_eq!(rate.take(0).apply(tat), None);
+    assert_eq!(rate.take(9 * minute).apply(tat), None);
+    // One token back after ten minutes.
+    tat = rate.take(10 * minute).apply(tat);
+    assert!(tat.is_some());
+    assert_eq!(rate.take(10 * minute).apply(tat), None);
+    // Idle long enough to be full again, but no fuller.
+    let mut tat = Some(100 * minute);
+    for _ in 0..3 {
+        tat = rate.take(1000 * minute).apply(tat);
+        assert!(tat.is_some());
+    }
+    assert_eq!(rate.take(1000 * minute).apply(tat), None);
+}
+
+#[test]
+fn test_burst_of_one() {
+    let rate = Rate { per_hour: 60, burst: 1 };
+    let tat = rate.take(0).apply(None);
+    assert_eq!(tat, Some(60_000));
+    assert_eq!(rate.take(59_999).apply(tat), None);
+    assert!(rate.take(60_000).apply(tat).is_some());
+}
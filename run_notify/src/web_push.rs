@@ -0,0 +1,239 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use aws_sdk_dynamodb::model::AttributeValue;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hkdf::Hkdf;
+use lambda_runtime::Error;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, SystemTime};
+
+const VAPID_PRIVATE_KEY: &str = "VAPID_PRIVATE_KEY";
+const VAPID_SUBJECT: &str = "VAPID_SUBJECT";
+
+/// Record size advertised in the aes128gcm header. Payloads are always sent
+/// as a single record, so this only needs to be larger than the payload.
+const RECORD_SIZE: u32 = 4096;
+/// How long a VAPID token is valid for; push services reject anything over 24h.
+const VAPID_EXPIRY: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// A browser push subscription, as stored on the push row by `register_push`.
+#[derive(Debug, Clone)]
+pub struct WebPushSubscription {
+    pub endpoint: String,
+    pub p256dh: Vec<u8>,
+    pub auth: Vec<u8>
+}
+
+impl WebPushSubscription {
+    pub fn from_item(item: &HashMap<String, AttributeValue>) -> Option<WebPushSubscription> {
+        if let (
+            Some(AttributeValue::S(endpoint)),
+            Some(AttributeValue::S(p256dh)),
+            Some(AttributeValue::S(auth))
+        ) = (item.get("endpoint"), item.get("p256dh"), item.get("auth")) {
+            Some(WebPushSubscription {
+                endpoint: endpoint.to_string(),
+                p256dh: URL_SAFE_NO_PAD.decode(p256dh.trim_end_matches('=')).ok()?,
+                auth: URL_SAFE_NO_PAD.decode(auth.trim_end_matches('=')).ok()?
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The application server's VAPID identity (RFC 8292).
+pub struct VapidKey {
+    signing_key: SigningKey,
+    subject: String
+}
+
+impl VapidKey {
+    /// Creates a key from a base64url encoded raw P-256 private scalar, which
+    /// is the format most web push tooling generates.
+    pub fn new(private_key: &str, subject: &str) -> Result<VapidKey, Error> {
+        let bytes = URL_SAFE_NO_PAD.decode(private_key.trim_end_matches('='))?;
+        let signing_key = SigningKey::from_slice(&bytes)
+            .map_err(|_| Error::from("invalid VAPID private key"))?;
+        Ok(VapidKey { signing_key, subject: subject.to_string() })
+    }
+
+    /// Loads the key from the environment, or `None` if web push isn't configured.
+    pub fn from_env() -> Result<Option<VapidKey>, Error> {
+        match env::var(VAPID_PRIVATE_KEY) {
+            Ok(private_key) => Ok(Some(VapidKey::new(&private_key, &env::var(VAPID_SUBJECT)?)?)),
+            Err(_) => Ok(None)
+        }
+    }
+
+    /// The uncompressed public key, as handed to browsers as `applicationServerKey`.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.signing_key.verifying_key().to_encoded_point(false).as_bytes().to_vec()
+    }
+
+    /// Builds the `Authorization` header value for a push to `endpoint`.
+    pub fn authorization(&self, endpoint: &str) -> Result<String, Error> {
+        let url = reqwest::Url::parse(endpoint)?;
+        let exp = (SystemTime::now() + VAPID_EXPIRY).duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let header = URL_SAFE_NO_PAD.encode("{\"typ\":\"JWT\",\"alg\":\"ES256\"}");
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_string(&serde_json::json!({
+            "aud": url.origin().ascii_serialization(),
+            "exp": exp,
+            "sub": self.subject
+        }))?);
+        let signing_input = [header, claims].join(".");
+        let signature: Signature = self.signing_key.sign(signing_input.as_bytes());
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            URL_SAFE_NO_PAD.encode(self.public_key())
+        ))
+    }
+}
+
+/// Encrypts `plaintext` for a subscription using the aes128gcm content
+/// encoding from RFC 8188, keyed as described in RFC 8291.
+pub fn encrypt(p256dh: &[u8], auth: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    encrypt_with(p256dh, auth, plaintext, &SecretKey::random(&mut OsRng), &salt)
+}
+
+fn encrypt_with(p256dh: &[u8], auth: &[u8], plaintext: &[u8], as_secret: &SecretKey, salt: &[u8; 16]) -> Result<Vec<u8>, Error> {
+    // The record also carries the delimiter byte and the 16 byte GCM tag.
+    if plaintext.len() + 17 > RECORD_SIZE as usize {
+        return Err(Error::from("web push payload too large"))
+    }
+    let ua_public = PublicKey::from_sec1_bytes(p256dh)
+        .map_err(|_| Error::from("invalid p256dh key"))?;
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let shared = p256::ecdh::diffie_hellman(as_secret.to_nonzero_scalar(), ua_public.as_affine());
+
+    // ikm = HKDF(auth, ecdh_secret, "WebPush: info" || 0x00 || ua_public || as_public)
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(p256dh);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|_| Error::from("HKDF expand failed"))?;
+
+    let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .map_err(|_| Error::from("HKDF expand failed"))?;
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .map_err(|_| Error::from("HKDF expand failed"))?;
+
+    // A single, final record: the plaintext followed by the 0x02 delimiter.
+    let mut record = plaintext.to_vec();
+    record.push(2);
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .map_err(|_| Error::from("invalid content encryption key"))?
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .map_err(|_| Error::from("encryption failed"))?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// The outcome of a delivery the push service accepted or refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+    /// The subscription has expired or been unsubscribed, and should be removed.
+    Gone
+}
+
+/// A delivery that failed, and whether trying it again might succeed.
+#[derive(Debug)]
+pub struct WebPushError {
+    pub reason: String,
+    pub transient: bool
+}
+
+impl std::error::Error for WebPushError {}
+
+impl std::fmt::Display for WebPushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl WebPushError {
+    fn permanent(e: Error) -> WebPushError {
+        WebPushError { reason: e.to_string(), transient: false }
+    }
+}
+
+pub struct WebPushSender {
+    client: reqwest::Client,
+    vapid: VapidKey,
+    ttl: Duration
+}
+
+impl WebPushSender {
+    pub fn new(vapid: VapidKey, ttl: Duration) -> WebPushSender {
+        WebPushSender { client: reqwest::Client::new(), vapid, ttl }
+    }
+
+    pub async fn send(&self, subscription: &WebPushSubscription, payload: &[u8]) -> Result<Delivery, WebPushError> {
+        let body = encrypt(&subscription.p256dh, &subscription.auth, payload).map_err(WebPushError::permanent)?;
+        let authorization = self.vapid.authorization(&subscription.endpoint).map_err(WebPushError::permanent)?;
+        let response = self.client.post(&subscription.endpoint)
+            .header("TTL", self.ttl.as_secs().to_string())
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header("Authorization", authorization)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| WebPushError { reason: e.to_string(), transient: true })?;
+        match response.status().as_u16() {
+            200..=299 => Ok(Delivery::Sent),
+            404 | 410 => Ok(Delivery::Gone),
+            status => Err(WebPushError {
+                reason: format!("push service returned {} for {}", status, subscription.endpoint),
+                transient: status == 429 || status >= 500
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+fn decode(s: &str) -> Vec<u8> {
+    URL_SAFE_NO_PAD.decode(s).unwrap()
+}
+
+// Test vector from RFC 8291, appendix A.
+#[test]
+fn test_encrypt_rfc8291() {
+    let as_secret = SecretKey::from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+    let salt: [u8; 16] = decode("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+    let body = encrypt_with(
+        &decode("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"),
+        &decode("BTBZMqHH6r4Tts7J_aSIgg"),
+        b"When I grow up, I want to be a watermelon",
+        &as_secret,
+        &salt
+    ).unwrap();
+    assert_eq!(
+        URL_SAFE_NO_PAD.encode(body),
+        "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+    );
+}
@@ -1,6 +1,24 @@
 use run_notify;
+use run_notify::web_push::{Delivery, VapidKey, WebPushSender, WebPushSubscription};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
 use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hkdf::Hkdf;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
 use lambda_runtime::{Context, LambdaEvent};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use selektor_common::push_scheduler::LocalScheduler;
+use serde_json::json;
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::time::Duration;
 
 #[test]
 fn test_handler() {
@@ -17,11 +35,112 @@ fn test_handler() {
             detail: None,
         },
         context: Default::default()
-    });
+    }, None);
     let res = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(future);
     println!("handler returned {:#?}", res)
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_deliver_handler() {
+    let push_scheduler = LocalScheduler::default();
+    let future = run_notify::deliver::deliver_handler(LambdaEvent {
+        payload: json!({"id": "test#missing"}),
+        context: Default::default()
+    }, &push_scheduler);
+    let res = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future);
+    println!("handler returned {:#?}", res);
+    // A schedule whose row is gone has its push schedule removed.
+    if let Ok(outcome) = res {
+        assert_eq!(outcome["outcome"], "removed");
+        assert!(push_scheduler.schedules().is_empty());
+    }
+}
+
+/// Decrypts an aes128gcm body the way a user agent would (RFC 8291).
+fn decrypt_web_push(ua_secret: &SecretKey, auth: &[u8], body: &[u8]) -> Vec<u8> {
+    let salt = &body[0..16];
+    let id_len = body[20] as usize;
+    let as_public_bytes = &body[21..21 + id_len];
+    let ciphertext = &body[21 + id_len..];
+    let as_public = PublicKey::from_sec1_bytes(as_public_bytes).unwrap();
+    let shared = p256::ecdh::diffie_hellman(ua_secret.to_nonzero_scalar(), as_public.as_affine());
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(ua_secret.public_key().to_encoded_point(false).as_bytes());
+    key_info.extend_from_slice(as_public_bytes);
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth), shared.raw_secret_bytes()).expand(&key_info, &mut ikm).unwrap();
+    let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek).unwrap();
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce).unwrap();
+    let mut plaintext = Aes128Gcm::new_from_slice(&cek).unwrap()
+        .decrypt(Nonce::from_slice(&nonce), ciphertext)
+        .unwrap();
+    assert_eq!(plaintext.pop(), Some(2));
+    plaintext
+}
+
+#[test]
+fn test_web_push_stub() {
+    let ua_secret = SecretKey::random(&mut OsRng);
+    let auth = [7u8; 16];
+    let vapid_secret = SecretKey::random(&mut OsRng);
+    let vapid = VapidKey::new(&URL_SAFE_NO_PAD.encode(vapid_secret.to_bytes()), "mailto:push@example.com").unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (headers, body) = runtime.block_on(async {
+        // A push service stub that records the one request it receives.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let headers = request.headers().clone();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        tx.send((headers, body.to_vec())).unwrap();
+                        Ok::<_, Infallible>(Response::builder().status(201).body(Body::empty()).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let endpoint = format!("http://{}/push/abc", server.local_addr());
+        tokio::spawn(server);
+
+        let subscription = WebPushSubscription {
+            endpoint,
+            p256dh: ua_secret.public_key().to_encoded_point(false).as_bytes().to_vec(),
+            auth: auth.to_vec()
+        };
+        let sender = WebPushSender::new(vapid, Duration::from_secs(300));
+        let delivery = sender.send(&subscription, b"{\"content-available\":1}").await.unwrap();
+        assert_eq!(delivery, Delivery::Sent);
+        rx.recv().unwrap()
+    });
+
+    assert_eq!(headers.get("content-encoding").unwrap(), "aes128gcm");
+    assert_eq!(headers.get("ttl").unwrap(), "300");
+    assert_eq!(decrypt_web_push(&ua_secret, &auth, &body), b"{\"content-available\":1}");
+
+    let authorization = headers.get("authorization").unwrap().to_str().unwrap();
+    let (token, key) = authorization.strip_prefix("vapid t=").unwrap().split_once(", k=").unwrap();
+    assert_eq!(URL_SAFE_NO_PAD.decode(key).unwrap(), vapid_secret.public_key().to_encoded_point(false).as_bytes());
+    let (signing_input, signature) = token.rsplit_once('.').unwrap();
+    let verifying_key = VerifyingKey::from_sec1_bytes(&URL_SAFE_NO_PAD.decode(key).unwrap()).unwrap();
+    let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).unwrap()).unwrap();
+    assert!(verifying_key.verify(signing_input.as_bytes(), &signature).is_ok());
+}
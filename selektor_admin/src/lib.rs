@@ -0,0 +1,217 @@
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_dynamodb as ddb;
+use aws_sdk_dynamodb::model::{
+    AttributeDefinition, BillingMode, CreateGlobalSecondaryIndexAction, GlobalSecondaryIndex,
+    GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, KeyType, LocalSecondaryIndex, Projection,
+    ProjectionType, ScalarAttributeType, TableDescription, TableStatus, TimeToLiveSpecification, TimeToLiveStatus
+};
+use selektor_common::dynamodb::{describe, described};
+use selektor_common::schema::{Index, IndexKind, KeySchema, Table, TABLES};
+use std::time::Duration;
+use tracing::info;
+
+pub mod migrations;
+pub mod queue;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// How long to wait for a table or index to become active.
+const ACTIVE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A deployment's tables: each schema table, named `<name>_<stage>`.
+pub struct Admin {
+    pub ddb_client: ddb::Client,
+    pub stage: String
+}
+
+impl Admin {
+    /// Connects to `endpoint` if given, e.g. `http://localhost:8000` for
+    /// DynamoDB Local, or else to DynamoDB in the configured region.
+    pub async fn new(endpoint: Option<String>, stage: String) -> Admin {
+        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+        let config = aws_config::from_env().region(region_provider).load().await;
+        let ddb_config = match endpoint {
+            Some(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
+            None => ddb::config::Builder::from(&config).build()
+        };
+        Admin { ddb_client: ddb::Client::from_conf(ddb_config), stage }
+    }
+
+    pub fn table_name(&self, table: &Table) -> String {
+        format!("{}_{}", table.name, self.stage)
+    }
+
+    /// Creates or updates every table, then applies any data migrations that
+    /// haven't been yet.
+    pub async fn migrate(&self) -> Result<(), Error> {
+        for table in TABLES.iter() {
+            self.provision(table).await?;
+        }
+        migrations::apply_pending(self).await
+    }
+
+    /// Brings one table in line with the schema: creates it if it's missing,
+    /// adds any missing global indexes, and turns on its TTL. Local indexes
+    /// can only be made with the table, so a table missing one is an error,
+    /// as is one keyed differently.
+    pub async fn provision(&self, table: &Table) -> Result<(), Error> {
+        let name = self.table_name(table);
+        let existing = match self.ddb_client.describe_table().table_name(name.to_owned()).send().await {
+            Ok(output) => output.table().cloned(),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_resource_not_found_exception() {
+                    None
+                } else {
+                    return Err(Error::from(e))
+                }
+            }
+        };
+        match existing {
+            None => self.create(table, &name).await?,
+            Some(description) => {
+                let deployed = described(&description, None);
+                for index in table.indexes.iter().filter(|index| !deployed.has_index(index.name)) {
+                    match index.kind {
+                        IndexKind::Global => self.create_global_index(table, &name, index).await?,
+                        IndexKind::Local => return Err(Error::from(format!(
+                            "{} is missing local index {}, which can only be made by recreating the table", name, index.name
+                        )))
+                    }
+                }
+            }
+        }
+        self.wait_until_active(&name).await?;
+        if let Some(attribute) = table.ttl_attribute {
+            self.enable_ttl(&name, attribute).await?;
+        }
+        table.verify(&describe(&self.ddb_client, &name).await?)
+            .map_err(|problems| format!("table {} doesn't match the schema: {}", name, problems))?;
+        Ok(())
+    }
+
+    async fn create(&self, table: &Table, name: &str) -> Result<(), Error> {
+        info!("creating {}", name);
+        let mut create = self.ddb_client.create_table()
+            .table_name(name.to_owned())
+            .billing_mode(BillingMode::PayPerRequest)
+            .set_attribute_definitions(Some(attribute_definitions(table)))
+            .set_key_schema(Some(key_schema(&table.key)));
+        for index in table.indexes {
+            create = match index.kind {
+                IndexKind::Local => create.local_secondary_indexes(LocalSecondaryIndex::builder()
+                    .index_name(index.name)
+                    .set_key_schema(Some(key_schema(&index.key)))
+                    .projection(projection())
+                    .build()),
+                IndexKind::Global => create.global_secondary_indexes(GlobalSecondaryIndex::builder()
+                    .index_name(index.name)
+                    .set_key_schema(Some(key_schema(&index.key)))
+                    .projection(projection())
+                    .build())
+            };
+        }
+        create.send().await?;
+        Ok(())
+    }
+
+    /// Adds a global index to an existing table, and waits for it to fill,
+    /// since DynamoDB only builds one at a time.
+    async fn create_global_index(&self, table: &Table, name: &str, index: &Index) -> Result<(), Error> {
+        info!("adding index {} to {}", index.name, name);
+        self.ddb_client.update_table()
+            .table_name(name.to_owned())
+            .set_attribute_definitions(Some(attribute_definitions(table)))
+            .global_secondary_index_updates(GlobalSecondaryIndexUpdate::builder()
+                .create(CreateGlobalSecondaryIndexAction::builder()
+                    .index_name(index.name)
+                    .set_key_schema(Some(key_schema(&index.key)))
+                    .projection(projection())
+                    .build())
+                .build())
+            .send()
+            .await?;
+        self.wait_until_active(name).await?;
+        Ok(())
+    }
+
+    /// Polls until the table and all its global indexes are active.
+    async fn wait_until_active(&self, name: &str) -> Result<TableDescription, Error> {
+        let started = std::time::Instant::now();
+        loop {
+            let output = self.ddb_client.describe_table().table_name(name.to_owned()).send().await?;
+            if let Some(description) = output.table() {
+                let indexes_active = description.global_secondary_indexes().unwrap_or_default().iter()
+                    .all(|index| index.index_status() == Some(&IndexStatus::Active));
+                if description.table_status() == Some(&TableStatus::Active) && indexes_active {
+                    return Ok(description.clone())
+                }
+            }
+            if started.elapsed() > ACTIVE_TIMEOUT {
+                return Err(Error::from(format!("{} didn't become active within {:?}", name, ACTIVE_TIMEOUT)))
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn enable_ttl(&self, name: &str, attribute: &str) -> Result<(), Error> {
+        let output = self.ddb_client.describe_time_to_live().table_name(name.to_owned()).send().await?;
+        let current = output.time_to_live_description();
+        let enabled = matches!(current.and_then(|d| d.time_to_live_status()), Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling));
+        if enabled {
+            return match current.and_then(|d| d.attribute_name()) {
+                Some(current) if current != attribute => Err(Error::from(format!(
+                    "{} expires items by {}, not {}", name, current, attribute
+                ))),
+                _ => Ok(())
+            }
+        }
+        info!("enabling TTL on {}.{}", name, attribute);
+        self.ddb_client.update_time_to_live()
+            .table_name(name.to_owned())
+            .time_to_live_specification(TimeToLiveSpecification::builder()
+                .enabled(true)
+                .attribute_name(attribute)
+                .build())
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+fn key_schema(key: &KeySchema) -> Vec<KeySchemaElement> {
+    let mut elements = vec![KeySchemaElement::builder().attribute_name(key.hash.name).key_type(KeyType::Hash).build()];
+    if let Some(range) = key.range {
+        elements.push(KeySchemaElement::builder().attribute_name(range.name).key_type(KeyType::Range).build());
+    }
+    elements
+}
+
+/// Definitions for every attribute the table or its indexes are keyed by.
+fn attribute_definitions(table: &Table) -> Vec<AttributeDefinition> {
+    let mut definitions: Vec<AttributeDefinition> = Vec::new();
+    let keys = std::iter::once(&table.key).chain(table.indexes.iter().map(|index| &index.key));
+    for attribute in keys.flat_map(|key| std::iter::once(key.hash).chain(key.range)) {
+        if !definitions.iter().any(|d| d.attribute_name() == Some(attribute.name)) {
+            definitions.push(AttributeDefinition::builder()
+                .attribute_name(attribute.name)
+                .attribute_type(ScalarAttributeType::from(attribute.attribute_type.code()))
+                .build());
+        }
+    }
+    definitions
+}
+
+fn projection() -> Projection {
+    Projection::builder().projection_type(ProjectionType::All).build()
+}
+
+#[test]
+fn test_attribute_definitions() {
+    let definitions = attribute_definitions(&selektor_common::schema::SCHEDULES);
+    let names: Vec<(&str, &str)> = definitions.iter()
+        .map(|d| (d.attribute_name().unwrap(), d.attribute_type().unwrap().as_str()))
+        .collect();
+    assert_eq!(names, vec![("part", "S"), ("id", "S"), ("entitlement", "S"), ("shard", "S"), ("next_fire", "N")]);
+}
@@ -0,0 +1,71 @@
+use selektor_admin::{Admin, Error};
+use std::env;
+use std::process::ExitCode;
+use tracing::error;
+
+const DYNAMODB_ENDPOINT: &str = "DYNAMODB_ENDPOINT";
+const TRACING_DEBUG: &str = "TRACING_DEBUG";
+
+const USAGE: &str = "usage: selektor-admin <command> [--endpoint URL] [--stage STAGE]
+
+  migrate        create or update every table and index, then apply pending
+                 data migrations
+  rebuild-queue  queue every schedule missing from the due queue
+  --endpoint     DynamoDB endpoint, e.g. http://localhost:8000 for DynamoDB
+                 Local; defaults to $DYNAMODB_ENDPOINT, then to AWS
+  --stage        suffix of the table names, e.g. schedule_dev; defaults to dev";
+
+struct Args {
+    command: String,
+    endpoint: Option<String>,
+    stage: String
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let command = args.next().ok_or_else(|| "no command given".to_string())?;
+    let mut endpoint = env::var(DYNAMODB_ENDPOINT).ok();
+    let mut stage = "dev".to_string();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--endpoint" => endpoint = Some(args.next().ok_or("--endpoint needs a URL")?),
+            "--stage" => stage = args.next().ok_or("--stage needs a name")?,
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+    Ok(Args { command, endpoint, stage })
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let admin = Admin::new(args.endpoint, args.stage).await;
+    match args.command.as_str() {
+        "migrate" => admin.migrate().await,
+        "rebuild-queue" => selektor_admin::queue::rebuild(&admin).await,
+        command => Err(Error::from(format!("unknown command {}", command)))
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_max_level(match env::var(TRACING_DEBUG) {
+            Ok(_) => tracing::Level::DEBUG,
+            Err(_) => tracing::Level::INFO
+        })
+        .with_target(false)
+        .init();
+
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2)
+        }
+    };
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
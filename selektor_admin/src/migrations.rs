@@ -0,0 +1,245 @@
+use crate::{Admin, Error};
+use aws_sdk_dynamodb::model::AttributeValue;
+use futures::StreamExt;
+use selektor_common::schedule::{is_legacy_bucket, Shards, LEGACY_BUCKET_SECONDS};
+use selektor_common::schema::{MIGRATIONS, SCHEDULES};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
+
+type Item = HashMap<String, AttributeValue>;
+
+/// A change to the data in the tables, applied once per deployment, in
+/// version order. Each is safe to run again should it stop partway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Migration {
+    /// Schedule times used to be 5 minute buckets since the epoch.
+    ScheduleTimesToSeconds,
+    /// Due schedules used to be found through a local index on `part`.
+    ShardDueIndex
+}
+
+/// Every migration, oldest first. New ones go on the end.
+pub const ALL: [Migration; 2] = [Migration::ScheduleTimesToSeconds, Migration::ShardDueIndex];
+
+impl Migration {
+    pub fn version(&self) -> u32 {
+        match self {
+            Migration::ScheduleTimesToSeconds => 1,
+            Migration::ShardDueIndex => 2
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Migration::ScheduleTimesToSeconds => "convert schedule times from 5 minute buckets to epoch seconds",
+            Migration::ShardDueIndex => "give every schedule a shard in the due index"
+        }
+    }
+
+    async fn apply(&self, admin: &Admin) -> Result<(), Error> {
+        match self {
+            Migration::ScheduleTimesToSeconds => schedule_times_to_seconds(admin).await,
+            Migration::ShardDueIndex => shard_due_index(admin).await
+        }
+    }
+}
+
+/// The versions recorded in the migrations table.
+async fn applied(admin: &Admin) -> Result<HashSet<u32>, Error> {
+    let mut pages = admin.ddb_client.scan()
+        .table_name(admin.table_name(&MIGRATIONS))
+        .into_paginator()
+        .send();
+    let mut versions = HashSet::new();
+    while let Some(page) = pages.next().await {
+        for item in page?.items().unwrap_or_default() {
+            match item.get("id") {
+                Some(AttributeValue::S(id)) => match u32::from_str(id) {
+                    Ok(version) => { versions.insert(version); },
+                    Err(_) => warn!("skipping unknown migration {}", id)
+                },
+                _ => warn!("skipping migration row with no id: {:?}", item)
+            }
+        }
+    }
+    Ok(versions)
+}
+
+async fn record(admin: &Admin, migration: &Migration) -> Result<(), Error> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    admin.ddb_client.put_item()
+        .table_name(admin.table_name(&MIGRATIONS))
+        .item("id", AttributeValue::S(migration.version().to_string()))
+        .item("description", AttributeValue::S(migration.description().to_string()))
+        .item("applied_at", AttributeValue::N(now.to_string()))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Applies every migration not yet recorded, in order, recording each one
+/// as it finishes.
+pub async fn apply_pending(admin: &Admin) -> Result<(), Error> {
+    let applied = applied(admin).await?;
+    for migration in ALL.iter().filter(|m| !applied.contains(&m.version())) {
+        info!("applying migration {}: {}", migration.version(), migration.description());
+        migration.apply(admin).await?;
+        record(admin, migration).await?;
+    }
+    Ok(())
+}
+
+/// The times on a schedule item that were counted in 5 minute buckets, and
+/// now need to be epoch seconds.
+const BUCKET_ATTRIBUTES: [&str; 4] = ["next_fire", "fire_interval", "last_fire", "deferred_from"];
+
+fn number(item: &Item, name: &str) -> Option<u64> {
+    match item.get(name) {
+        Some(AttributeValue::N(n)) => u64::from_str(n).ok(),
+        _ => None
+    }
+}
+
+/// Whether an item's times are still buckets. `next_fire` says so, or
+/// `last_fire` for a cron schedule that's stopped firing.
+fn is_legacy(item: &Item) -> bool {
+    match number(item, "next_fire").or_else(|| number(item, "last_fire")) {
+        Some(time) => is_legacy_bucket(time),
+        None => false
+    }
+}
+
+/// Converts one schedule item's bucket times to seconds, on the condition
+/// that they haven't changed since it was read. Returns whether it did.
+async fn migrate_item(admin: &Admin, table_name: &str, item: &Item) -> Result<bool, Error> {
+    let mut update = admin.ddb_client.update_item()
+        .table_name(table_name.to_owned())
+        .set_key(Some(item.iter()
+            .filter(|(k, _)| k.as_str() == "part" || k.as_str() == "id")
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()));
+    let mut sets = Vec::new();
+    let mut conditions = Vec::new();
+    for (i, name) in BUCKET_ATTRIBUTES.iter().enumerate() {
+        if let Some(value) = number(item, name) {
+            sets.push(format!("#a{} = :new{}", i, i));
+            conditions.push(format!("#a{} = :old{}", i, i));
+            update = update
+                .expression_attribute_names(format!("#a{}", i), name.to_string())
+                .expression_attribute_values(format!(":new{}", i), AttributeValue::N((value * LEGACY_BUCKET_SECONDS).to_string()))
+                .expression_attribute_values(format!(":old{}", i), AttributeValue::N(value.to_string()));
+        }
+    }
+    let result = update
+        .update_expression(format!("SET {}", sets.join(", ")))
+        .condition_expression(conditions.join(" AND "))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            let e = e.into_service_error();
+            if e.is_conditional_check_failed_exception() {
+                warn!("schedule changed while migrating, leaving it: {:?}", item.get("id"));
+                Ok(false)
+            } else {
+                Err(Error::from(e))
+            }
+        }
+    }
+}
+
+/// Rewrites every schedule whose times are still 5 minute buckets. Disable
+/// the `run_notify` rule while this runs; it refuses to fire schedules that
+/// haven't been converted.
+async fn schedule_times_to_seconds(admin: &Admin) -> Result<(), Error> {
+    let table_name = admin.table_name(&SCHEDULES);
+    let mut pages = admin.ddb_client.scan()
+        .table_name(table_name.to_owned())
+        .into_paginator()
+        .send();
+    let mut migrated = 0;
+    let mut skipped = 0;
+    while let Some(page) = pages.next().await {
+        for item in page?.items().unwrap_or_default() {
+            if !is_legacy(item) {
+                debug!("already in seconds: {:?}", item.get("id"));
+                continue
+            }
+            if migrate_item(admin, &table_name, item).await? {
+                migrated += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+    }
+    info!("migrated {} schedules, {} changed underneath", migrated, skipped);
+    Ok(())
+}
+
+/// Puts every schedule into its shard of the due index, which it's missing
+/// from until it has one. Rows already in the right shard are left alone.
+/// Run this before deploying the `run_notify` that reads the due index, with
+/// the same `SCHEDULE_SHARDS`.
+async fn shard_due_index(admin: &Admin) -> Result<(), Error> {
+    let shards = Shards::from_env()?;
+    let table_name = admin.table_name(&SCHEDULES);
+    let mut pages = admin.ddb_client.scan()
+        .table_name(table_name.to_owned())
+        .into_paginator()
+        .send();
+    let mut sharded = 0;
+    while let Some(page) = pages.next().await {
+        for item in page?.items().unwrap_or_default() {
+            // The version rows have no entitlement, and aren't schedules.
+            let (part, id) = match (item.get("part"), item.get("id"), item.get("entitlement")) {
+                (Some(AttributeValue::S(part)), Some(AttributeValue::S(id)), Some(_)) => (part, id),
+                _ => continue
+            };
+            let shard = shards.key(part, id);
+            if item.get("shard") == Some(&AttributeValue::S(shard.to_owned())) {
+                continue
+            }
+            let result = admin.ddb_client.update_item()
+                .table_name(table_name.to_owned())
+                .key("part", AttributeValue::S(part.to_owned()))
+                .key("id", AttributeValue::S(id.to_owned()))
+                .update_expression("SET #shard = :shard")
+                .condition_expression("attribute_exists(#id)")
+                .expression_attribute_names("#shard", "shard")
+                .expression_attribute_names("#id", "id")
+                .expression_attribute_values(":shard", AttributeValue::S(shard))
+                .send()
+                .await;
+            match result {
+                Ok(_) => sharded += 1,
+                Err(e) => {
+                    let e = e.into_service_error();
+                    if !e.is_conditional_check_failed_exception() {
+                        return Err(Error::from(e))
+                    }
+                    debug!("schedule removed while sharding: {}", id);
+                }
+            }
+        }
+    }
+    info!("sharded {} schedules", sharded);
+    Ok(())
+}
+
+#[test]
+fn test_is_legacy() {
+    let item = |name: &str, value: &str| Item::from([(name.to_string(), AttributeValue::N(value.to_string()))]);
+    assert!(is_legacy(&item("next_fire", "5612345")));
+    assert!(!is_legacy(&item("next_fire", "1684000000")));
+    assert!(is_legacy(&item("last_fire", "5612345")));
+    assert!(!is_legacy(&Item::new()));
+}
+
+#[test]
+fn test_versions() {
+    let versions: Vec<u32> = ALL.iter().map(Migration::version).collect();
+    assert!(versions.windows(2).all(|w| w[0] < w[1]));
+}
@@ -0,0 +1,72 @@
+use crate::{Admin, Error};
+use aws_sdk_dynamodb::model::AttributeValue;
+use futures::StreamExt;
+use selektor_common::schedule::{is_legacy_bucket, queue_key, Shards, Tick, QUEUE_RETENTION_SECONDS};
+use selektor_common::schema::{DUE_QUEUE, SCHEDULES};
+use std::str::FromStr;
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+/// Checks the due queue against the schedules table, and writes any entry
+/// that's missing: one for each schedule, in its shard's bucket for its
+/// `next_fire`, or for the next tick if that's already gone by. Entries
+/// that are already right are left alone, and stale ones are left to
+/// expire. Run it after switching `SCHEDULER` to `queue`, or whenever a
+/// tick may have been missed, with the same `TICK_SECONDS` and
+/// `SCHEDULE_SHARDS` as the lambdas.
+pub async fn rebuild(admin: &Admin) -> Result<(), Error> {
+    let tick = Tick::from_env()?;
+    let shards = Shards::from_env()?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let queue_table = admin.table_name(&DUE_QUEUE);
+    let mut pages = admin.ddb_client.scan()
+        .table_name(admin.table_name(&SCHEDULES))
+        .into_paginator()
+        .send();
+    let mut queued = 0;
+    let mut present = 0;
+    while let Some(page) = pages.next().await {
+        for item in page?.items().unwrap_or_default() {
+            // Version rows, and cron schedules that have stopped, have no
+            // next_fire, and are never due.
+            let (part, id, next_fire) = match (item.get("part"), item.get("id"), item.get("next_fire")) {
+                (Some(AttributeValue::S(part)), Some(AttributeValue::S(id)), Some(AttributeValue::N(next_fire))) => {
+                    (part, id, u64::from_str(next_fire)?)
+                },
+                _ => continue
+            };
+            if is_legacy_bucket(next_fire) {
+                debug!("not queueing {}, its times are still buckets", id);
+                continue
+            }
+            let bucket = tick.queue_bucket(next_fire, now);
+            let result = admin.ddb_client.put_item()
+                .table_name(queue_table.to_owned())
+                .item("bucket", AttributeValue::S(queue_key(&shards.key(part, id), bucket)))
+                .item("id", AttributeValue::S(id.to_owned()))
+                .item("next_fire", AttributeValue::N(next_fire.to_string()))
+                .item("expires", AttributeValue::N((bucket + QUEUE_RETENTION_SECONDS).to_string()))
+                .condition_expression("attribute_not_exists(#id) OR #next_fire <> :next_fire")
+                .expression_attribute_names("#id", "id")
+                .expression_attribute_names("#next_fire", "next_fire")
+                .expression_attribute_values(":next_fire", AttributeValue::N(next_fire.to_string()))
+                .send()
+                .await;
+            match result {
+                Ok(_) => {
+                    debug!("queued {} for {}", id, bucket);
+                    queued += 1
+                },
+                Err(e) => {
+                    let e = e.into_service_error();
+                    if !e.is_conditional_check_failed_exception() {
+                        return Err(Error::from(e))
+                    }
+                    present += 1;
+                }
+            }
+        }
+    }
+    info!("queued {} schedules that were missing from the due queue, {} already queued", queued, present);
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use crate::schema::{DescribedTable, IndexKind, Table};
+use aws_sdk_dynamodb as ddb;
+use aws_sdk_dynamodb::model::{KeySchemaElement, TableDescription, TimeToLiveDescription, TimeToLiveStatus};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+fn add_keys(deployed: &mut DescribedTable, index: Option<(IndexKind, &str)>, keys: Option<&[KeySchemaElement]>) {
+    for key in keys.unwrap_or_default() {
+        deployed.add_key(index, key.attribute_name().unwrap_or_default(), key.key_type().map(|t| t.as_str()).unwrap_or_default());
+    }
+}
+
+/// A table as `DescribeTable` and `DescribeTimeToLive` report it.
+pub fn described(description: &TableDescription, ttl: Option<&TimeToLiveDescription>) -> DescribedTable {
+    let mut deployed = DescribedTable::default();
+    add_keys(&mut deployed, None, description.key_schema());
+    for index in description.local_secondary_indexes().unwrap_or_default() {
+        add_keys(&mut deployed, Some((IndexKind::Local, index.index_name().unwrap_or_default())), index.key_schema());
+    }
+    for index in description.global_secondary_indexes().unwrap_or_default() {
+        add_keys(&mut deployed, Some((IndexKind::Global, index.index_name().unwrap_or_default())), index.key_schema());
+    }
+    if let Some(ttl) = ttl {
+        if matches!(ttl.time_to_live_status(), Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)) {
+            deployed.set_ttl_attribute(ttl.attribute_name());
+        }
+    }
+    deployed
+}
+
+/// Describes a deployed table, its TTL included.
+pub async fn describe(ddb_client: &ddb::Client, table_name: &str) -> Result<DescribedTable, Error> {
+    let table = ddb_client.describe_table().table_name(table_name).send().await?;
+    let ttl = ddb_client.describe_time_to_live().table_name(table_name).send().await?;
+    Ok(match table.table() {
+        Some(description) => described(description, ttl.time_to_live_description()),
+        None => DescribedTable::default()
+    })
+}
+
+/// Fails unless each of a function's tables, given by its deployed name, is
+/// deployed the way `schema` describes, so a mismatch stops the function at
+/// startup rather than failing its queries later.
+pub async fn check_tables(ddb_client: &ddb::Client, tables: &[(String, Table)]) -> Result<(), Error> {
+    for (table_name, table) in tables {
+        table.verify(&describe(ddb_client, table_name).await?)
+            .map_err(|problems| format!("table {} doesn't match the schema: {}", table_name, problems))?;
+    }
+    Ok(())
+}
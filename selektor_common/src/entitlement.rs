@@ -0,0 +1,135 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// How long, in seconds, an entitlement must have been over before
+/// `purge_expired` removes its schedules. Defaults to a day.
+pub const PURGE_GRACE_SECONDS: &str = "PURGE_GRACE_SECONDS";
+
+/// The longest, in seconds, the App Store keeps retrying a failed renewal:
+/// 60 days. An entitlement still `grace` or `billing_retry` this long after
+/// it ended missed the notification saying the App Store gave up.
+pub const MAX_RENEWAL_RETRY_SECONDS: u64 = 60 * 24 * 60 * 60;
+
+/// An entitlement's `status`, following the App Store's subscription
+/// statuses. Entitlements written before there was one have none, and are
+/// judged by `ends` alone, like active ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntitlementStatus {
+    Active,
+    /// Renewal failed, but the App Store's billing grace period keeps the
+    /// subscription going while it retries.
+    Grace,
+    /// Renewal failed, and the App Store is still retrying it.
+    BillingRetry,
+    Expired,
+    /// Refunded, or otherwise taken back; `ends` is when.
+    Revoked
+}
+
+impl EntitlementStatus {
+    /// Whether the App Store may still renew it, so it isn't over yet even
+    /// when `ends` has passed.
+    pub fn may_renew(&self) -> bool {
+        matches!(self, EntitlementStatus::Grace | EntitlementStatus::BillingRetry)
+    }
+}
+
+impl Display for EntitlementStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntitlementStatus::Active => write!(f, "active"),
+            EntitlementStatus::Grace => write!(f, "grace"),
+            EntitlementStatus::BillingRetry => write!(f, "billing_retry"),
+            EntitlementStatus::Expired => write!(f, "expired"),
+            EntitlementStatus::Revoked => write!(f, "revoked")
+        }
+    }
+}
+
+impl FromStr for EntitlementStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(EntitlementStatus::Active),
+            "grace" => Ok(EntitlementStatus::Grace),
+            "billing_retry" => Ok(EntitlementStatus::BillingRetry),
+            "expired" => Ok(EntitlementStatus::Expired),
+            "revoked" => Ok(EntitlementStatus::Revoked),
+            _ => Err(format!("invalid entitlement status: {}", s))
+        }
+    }
+}
+
+/// How long after an entitlement ends its schedules are kept, so a renewal
+/// that's a little late doesn't cost the user their schedules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PurgeGrace(u64);
+
+impl Default for PurgeGrace {
+    fn default() -> Self {
+        PurgeGrace(24 * 60 * 60)
+    }
+}
+
+impl PurgeGrace {
+    pub fn new(seconds: u64) -> PurgeGrace {
+        PurgeGrace(seconds)
+    }
+
+    pub fn from_env() -> Result<PurgeGrace, String> {
+        match env::var(PURGE_GRACE_SECONDS) {
+            Ok(seconds) => u64::from_str(&seconds).map(PurgeGrace::new)
+                .map_err(|_| format!("invalid {}: {}", PURGE_GRACE_SECONDS, seconds)),
+            Err(_) => Ok(PurgeGrace::default())
+        }
+    }
+
+    /// The latest `ends`, in epoch millis, an entitlement can have and be
+    /// purged at `now`, also in millis.
+    pub fn cutoff(&self, now: u64) -> u64 {
+        now.saturating_sub(self.0 * 1000)
+    }
+
+    /// Whether an entitlement's schedules can be purged at `now`: it's over
+    /// for good, and ended, at `ends`, more than the grace period ago. One the
+    /// App Store may still renew is over once it can't be retrying any more.
+    /// Times are in epoch millis.
+    pub fn purgeable(&self, status: Option<EntitlementStatus>, ends: u64, now: u64) -> bool {
+        let cutoff = match status {
+            Some(status) if status.may_renew() => self.cutoff(now).saturating_sub(MAX_RENEWAL_RETRY_SECONDS * 1000),
+            _ => self.cutoff(now)
+        };
+        ends < cutoff
+    }
+}
+
+#[test]
+fn test_status_round_trip() {
+    use EntitlementStatus::*;
+    for status in [Active, Grace, BillingRetry, Expired, Revoked] {
+        assert_eq!(EntitlementStatus::from_str(&status.to_string()), Ok(status));
+    }
+    assert!(EntitlementStatus::from_str("lapsed").is_err());
+}
+
+#[test]
+fn test_purgeable() {
+    let grace = PurgeGrace::new(60);
+    let now = 1_700_000_000_000;
+    assert!(grace.purgeable(Some(EntitlementStatus::Expired), now - 61_000, now));
+    assert!(grace.purgeable(Some(EntitlementStatus::Revoked), now - 61_000, now));
+    assert!(grace.purgeable(None, now - 61_000, now));
+    // A renewal that's late, but within the grace period.
+    assert!(!grace.purgeable(Some(EntitlementStatus::Active), now - 59_000, now));
+    assert!(grace.purgeable(Some(EntitlementStatus::Active), now - 61_000, now));
+    // The App Store is still trying to bill them.
+    assert!(!grace.purgeable(Some(EntitlementStatus::Grace), now - 86_400_000, now));
+    assert!(!grace.purgeable(Some(EntitlementStatus::BillingRetry), now - 86_400_000, now));
+    // Until the longest the App Store retries for is over.
+    let gave_up = now - MAX_RENEWAL_RETRY_SECONDS * 1000 - 61_000;
+    assert!(grace.purgeable(Some(EntitlementStatus::Grace), gave_up, now));
+    assert!(grace.purgeable(Some(EntitlementStatus::BillingRetry), gave_up, now));
+    assert!(!grace.purgeable(Some(EntitlementStatus::BillingRetry), gave_up + 2_000, now));
+}
@@ -0,0 +1,11 @@
+#[cfg(feature = "dynamodb")]
+pub mod dynamodb;
+pub mod entitlement;
+pub mod local_time;
+pub mod payload;
+pub mod schema;
+pub mod schedule;
+#[cfg(feature = "dynamodb")]
+pub mod schedule_item;
+#[cfg(feature = "eventbridge")]
+pub mod push_scheduler;
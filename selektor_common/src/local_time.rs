@@ -0,0 +1,128 @@
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+
+pub fn parse_time_zone(name: &str) -> Result<Tz, String> {
+    Tz::from_str(name).map_err(|_| format!("unknown time zone '{}'", name))
+}
+
+/// Pins a wall-clock time in `time_zone` to an instant. A time repeated when
+/// the clocks go back is taken the first time round, and one skipped when they
+/// go forward is moved past the gap, so 02:30 on a spring-forward night in New
+/// York is 03:30.
+pub fn resolve(time_zone: Tz, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    match time_zone.from_local_datetime(&local) {
+        LocalResult::Single(t) => Some(t.with_timezone(&Utc)),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest.with_timezone(&Utc)),
+        LocalResult::None => time_zone.from_local_datetime(&(local - Duration::hours(1)))
+            .earliest()
+            .map(|t| t.with_timezone(&Utc) + Duration::hours(1))
+    }
+}
+
+/// An epoch seconds time as an instant.
+pub fn instant(time: u64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(time as i64, 0).single()
+}
+
+/// A window of local time during which nothing is sent, e.g. 22:00 to 07:00.
+/// Windows that end before they start run past midnight.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QuietHours {
+    #[serde(with = "hh_mm")]
+    pub start: NaiveTime,
+    #[serde(with = "hh_mm")]
+    pub end: NaiveTime
+}
+
+impl QuietHours {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start == self.end {
+            Err(format!("quiet hours starting and ending at {} are empty", self.start.format("%H:%M")))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// When the window containing `local` ends.
+    fn end_after(&self, local: NaiveDateTime) -> NaiveDateTime {
+        let end = local.date().and_time(self.end);
+        if end > local { end } else { end + Duration::days(1) }
+    }
+}
+
+/// If `time` falls in quiet hours in `time_zone`, when they end, following on
+/// through any windows that overlap. `None` if it doesn't. Times are epoch
+/// seconds.
+pub fn quiet_until(time: u64, time_zone: Tz, quiet_hours: &[QuietHours]) -> Option<u64> {
+    let mut deferred = None;
+    let mut current = time;
+    // Each window can only push the tick out once.
+    for _ in 0..quiet_hours.len() {
+        let local = instant(current)?.with_timezone(&time_zone).naive_local();
+        let window = match quiet_hours.iter().find(|w| w.contains(local.time())) {
+            Some(window) => window,
+            None => break
+        };
+        current = resolve(time_zone, window.end_after(local))?.timestamp().max(0) as u64;
+        deferred = Some(current);
+    }
+    deferred
+}
+
+mod hh_mm {
+    use super::*;
+
+    const FORMAT: &str = "%H:%M";
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format(FORMAT).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&s, FORMAT).map_err(|e| serde::de::Error::custom(format!("invalid time '{}': {}", s, e)))
+    }
+}
+
+#[cfg(test)]
+fn secs(rfc3339: &str) -> u64 {
+    DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp() as u64
+}
+
+#[test]
+fn test_resolve_across_dst() {
+    let new_york = parse_time_zone("America/New_York").unwrap();
+    let local = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+    // Clocks go forward at 02:00 on 2023-03-12, so 02:30 doesn't happen.
+    assert_eq!(resolve(new_york, local("2023-03-12 02:30")).unwrap().to_rfc3339(), "2023-03-12T07:30:00+00:00");
+    // And back at 02:00 on 2023-11-05, so 01:30 happens twice.
+    assert_eq!(resolve(new_york, local("2023-11-05 01:30")).unwrap().to_rfc3339(), "2023-11-05T05:30:00+00:00");
+    assert_eq!(resolve(new_york, local("2023-11-05 08:00")).unwrap().to_rfc3339(), "2023-11-05T13:00:00+00:00");
+}
+
+#[test]
+fn test_quiet_until() {
+    let berlin = parse_time_zone("Europe/Berlin").unwrap();
+    let overnight: QuietHours = serde_json::from_str("{\"start\":\"22:00\",\"end\":\"07:00\"}").unwrap();
+    let lunch: QuietHours = serde_json::from_str("{\"start\":\"06:30\",\"end\":\"08:00\"}").unwrap();
+    // 23:00 in Berlin, winter time.
+    assert_eq!(quiet_until(secs("2023-01-10T22:00:00Z"), berlin, &[overnight]), Some(secs("2023-01-11T06:00:00Z")));
+    // 01:00, on the night the clocks go forward: the window still ends at 07:00 local.
+    assert_eq!(quiet_until(secs("2023-03-26T00:00:00Z"), berlin, &[overnight]), Some(secs("2023-03-26T05:00:00Z")));
+    // Overlapping windows run on into each other.
+    assert_eq!(quiet_until(secs("2023-01-10T22:00:00Z"), berlin, &[overnight, lunch]), Some(secs("2023-01-11T07:00:00Z")));
+    // 12:00 isn't quiet.
+    assert_eq!(quiet_until(secs("2023-01-10T11:00:00Z"), berlin, &[overnight, lunch]), None);
+    assert!(QuietHours { start: overnight.start, end: overnight.start }.validate().is_err());
+    assert_eq!(serde_json::to_string(&overnight).unwrap(), "{\"start\":\"22:00\",\"end\":\"07:00\"}");
+}
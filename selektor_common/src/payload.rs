@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fmt::{Display, Formatter};
+
+/// The largest payload APNs accepts for a regular (non-VoIP) push.
+pub const APNS_MAX_PAYLOAD: usize = 4096;
+/// The largest payload FCM accepts.
+pub const GCM_MAX_PAYLOAD: usize = 4096;
+
+/// Sent when a schedule has no template: a silent push telling the app to refresh.
+const BACKGROUND_APS: &str = "content-available";
+
+/// A notification template carried by a schedule. With no alert fields set the
+/// push is delivered as a background push, like schedules without a template.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PayloadTemplate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub badge: Option<u32>,
+    #[serde(rename = "thread-id", default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    /// Custom keys delivered alongside the notification, e.g. the monitored URL ID.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub data: Map<String, Value>
+}
+
+#[derive(Debug)]
+pub struct PayloadError {
+    pub reason: String
+}
+
+impl std::error::Error for PayloadError {}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// The SNS platforms a message can be rendered for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Platform {
+    Apns,
+    ApnsSandbox,
+    Gcm
+}
+
+impl Platform {
+    /// The key for this platform in an SNS `json` message structure.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Platform::Apns => "APNS",
+            Platform::ApnsSandbox => "APNS_SANDBOX",
+            Platform::Gcm => "GCM"
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Platform> {
+        match key {
+            "APNS" => Some(Platform::Apns),
+            "APNS_SANDBOX" => Some(Platform::ApnsSandbox),
+            "GCM" => Some(Platform::Gcm),
+            _ => None
+        }
+    }
+}
+
+impl PayloadTemplate {
+    /// Whether this shows something to the user, rather than waking the app.
+    pub fn is_alert(&self) -> bool {
+        self.title.is_some() || self.body.is_some() || self.sound.is_some() || self.badge.is_some()
+    }
+
+    /// The APNs payload: an `aps` dictionary, with custom data at the top level.
+    pub fn apns(&self) -> Value {
+        let mut aps = Map::new();
+        if self.is_alert() {
+            let mut alert = Map::new();
+            if let Some(title) = &self.title {
+                alert.insert("title".to_string(), json!(title));
+            }
+            if let Some(body) = &self.body {
+                alert.insert("body".to_string(), json!(body));
+            }
+            if !alert.is_empty() {
+                aps.insert("alert".to_string(), Value::Object(alert));
+            }
+            if let Some(sound) = &self.sound {
+                aps.insert("sound".to_string(), json!(sound));
+            }
+            if let Some(badge) = self.badge {
+                aps.insert("badge".to_string(), json!(badge));
+            }
+        } else {
+            aps.insert(BACKGROUND_APS.to_string(), json!(1));
+        }
+        if let Some(thread_id) = &self.thread_id {
+            aps.insert("thread-id".to_string(), json!(thread_id));
+        }
+        let mut payload = self.data.clone();
+        payload.insert("aps".to_string(), Value::Object(aps));
+        Value::Object(payload)
+    }
+
+    /// The FCM payload. FCM only allows string values in `data`, so anything
+    /// else is sent as its JSON text.
+    pub fn gcm(&self) -> Value {
+        let mut payload = Map::new();
+        if self.is_alert() {
+            let mut notification = Map::new();
+            if let Some(title) = &self.title {
+                notification.insert("title".to_string(), json!(title));
+            }
+            if let Some(body) = &self.body {
+                notification.insert("body".to_string(), json!(body));
+            }
+            if let Some(sound) = &self.sound {
+                notification.insert("sound".to_string(), json!(sound));
+            }
+            if let Some(thread_id) = &self.thread_id {
+                notification.insert("tag".to_string(), json!(thread_id));
+            }
+            payload.insert("notification".to_string(), Value::Object(notification));
+        }
+        let mut data: Map<String, Value> = self.data.iter()
+            .map(|(k, v)| (k.to_string(), match v {
+                Value::String(s) => json!(s),
+                other => json!(other.to_string())
+            }))
+            .collect();
+        if !self.is_alert() {
+            data.insert(BACKGROUND_APS.to_string(), json!("1"));
+        }
+        payload.insert("data".to_string(), Value::Object(data));
+        Value::Object(payload)
+    }
+
+    /// The payload for a Web Push delivery, which the service worker decodes itself.
+    pub fn web_push(&self) -> Value {
+        if self.is_alert() || !self.data.is_empty() {
+            serde_json::to_value(self).unwrap_or(Value::Null)
+        } else {
+            json!({"content-available": 1})
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), PayloadError> {
+        if self.data.contains_key("aps") {
+            return Err(PayloadError { reason: "data may not contain the reserved key 'aps'".to_string() })
+        }
+        let apns_len = self.apns().to_string().len();
+        if apns_len > APNS_MAX_PAYLOAD {
+            return Err(PayloadError {
+                reason: format!("APNs payload is {} bytes, the limit is {}", apns_len, APNS_MAX_PAYLOAD)
+            })
+        }
+        let gcm_len = self.gcm().to_string().len();
+        if gcm_len > GCM_MAX_PAYLOAD {
+            return Err(PayloadError {
+                reason: format!("GCM payload is {} bytes, the limit is {}", gcm_len, GCM_MAX_PAYLOAD)
+            })
+        }
+        Ok(())
+    }
+
+    /// The APNs push type and priority SNS should send this with.
+    pub fn apns_push_type(&self) -> (&'static str, &'static str) {
+        if self.is_alert() {
+            ("alert", "10")
+        } else {
+            ("background", "5")
+        }
+    }
+
+    /// Renders an SNS message for `MessageStructure=json`, with one envelope
+    /// per platform. Each envelope is itself a JSON string, as SNS requires.
+    pub fn sns_message(&self, platforms: &[Platform]) -> Result<String, PayloadError> {
+        self.validate()?;
+        let mut message = Map::new();
+        // SNS requires a non-empty default for endpoints without a platform envelope.
+        let default = self.body.as_ref().or(self.title.as_ref()).map(|s| s.as_str()).unwrap_or("refresh");
+        message.insert("default".to_string(), json!(default));
+        for platform in platforms {
+            let envelope = match platform {
+                Platform::Apns | Platform::ApnsSandbox => self.apns(),
+                Platform::Gcm => self.gcm()
+            };
+            message.insert(platform.key().to_string(), json!(envelope.to_string()));
+        }
+        Ok(Value::Object(message).to_string())
+    }
+}
+
+#[test]
+fn test_background_envelopes() {
+    let message = PayloadTemplate::default().sns_message(&[Platform::Apns, Platform::Gcm]).unwrap();
+    let message: Value = serde_json::from_str(&message).unwrap();
+    assert_eq!(message["APNS"], json!("{\"aps\":{\"content-available\":1}}"));
+    assert_eq!(message["GCM"], json!("{\"data\":{\"content-available\":\"1\"}}"));
+    assert_eq!(PayloadTemplate::default().apns_push_type(), ("background", "5"));
+}
+
+#[test]
+fn test_alert_envelopes() {
+    let template: PayloadTemplate = serde_json::from_str(
+        "{\"title\":\"Changed\",\"body\":\"example.com changed\",\"sound\":\"default\",\"badge\":2,\
+        \"thread-id\":\"site-1\",\"data\":{\"url_id\":42}}"
+    ).unwrap();
+    assert_eq!(template.apns(), json!({
+        "aps": {
+            "alert": {"title": "Changed", "body": "example.com changed"},
+            "sound": "default",
+            "badge": 2,
+            "thread-id": "site-1"
+        },
+        "url_id": 42
+    }));
+    assert_eq!(template.gcm()["data"], json!({"url_id": "42"}));
+    assert_eq!(template.apns_push_type(), ("alert", "10"));
+    let message: Value = serde_json::from_str(&template.sns_message(&[Platform::ApnsSandbox]).unwrap()).unwrap();
+    assert_eq!(message["default"], json!("example.com changed"));
+    assert!(message.get("APNS").is_none());
+    assert!(message["APNS_SANDBOX"].is_string());
+}
+
+#[test]
+fn test_validate_limits() {
+    let mut template = PayloadTemplate { body: Some("x".repeat(APNS_MAX_PAYLOAD)), ..Default::default() };
+    assert!(template.validate().is_err());
+    template.body = Some("short".to_string());
+    assert!(template.validate().is_ok());
+    template.data.insert("aps".to_string(), json!({}));
+    assert!(template.validate().is_err());
+}
@@ -0,0 +1,407 @@
+use crate::local_time::QuietHours;
+use crate::schedule::{stable_hash, Scheduler};
+use async_trait::async_trait;
+use aws_credential_types::cache::{ProvideCachedCredentials, SharedCredentialsCache};
+use aws_types::SdkConfig;
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+pub const SCHEDULER_TARGET_ARN: &str = "SCHEDULER_TARGET_ARN";
+pub const SCHEDULER_ROLE_ARN: &str = "SCHEDULER_ROLE_ARN";
+pub const SCHEDULER_GROUP: &str = "SCHEDULER_GROUP";
+pub const SCHEDULER_ENDPOINT: &str = "SCHEDULER_ENDPOINT";
+const SERVICE: &str = "scheduler";
+const DEFAULT_GROUP: &str = "default";
+
+#[derive(Debug)]
+pub struct SchedulerError {
+    pub reason: String
+}
+
+impl Display for SchedulerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+impl From<reqwest::Error> for SchedulerError {
+    fn from(e: reqwest::Error) -> Self {
+        SchedulerError { reason: e.to_string() }
+    }
+}
+
+/// When a schedule's push is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    /// Once, at this epoch second. Each delivery schedules the next one.
+    At(u64),
+    /// Every `interval` seconds from `start`, until it's deleted.
+    Rate { start: u64, interval: u64 }
+}
+
+impl Timing {
+    /// How a schedule next due at `next_fire` is timed. `interval` is its
+    /// `fire_interval`, for schedules that fire at a fixed interval and
+    /// nothing moves; only whole minutes can be a rate. Anything else is
+    /// scheduled one occurrence at a time.
+    pub fn new(next_fire: u64, interval: Option<u64>) -> Timing {
+        match interval {
+            Some(interval) if interval > 0 && interval % 60 == 0 => Timing::Rate { start: next_fire, interval },
+            _ => Timing::At(next_fire)
+        }
+    }
+
+    /// How a schedule next due at `next_fire` is timed. Quiet hours move
+    /// occurrences, and cron occurrences aren't evenly spaced, so only a
+    /// plain `fire_interval` can be a rate.
+    pub fn for_schedule(next_fire: u64, fire_interval: Option<u64>, cron: bool, quiet_hours: &[QuietHours]) -> Timing {
+        match (cron, quiet_hours.is_empty()) {
+            (false, true) => Timing::new(next_fire, fire_interval),
+            _ => Timing::new(next_fire, None)
+        }
+    }
+
+    /// The schedule expression, in UTC.
+    pub fn expression(&self) -> String {
+        match self {
+            Timing::At(time) => {
+                let time = Utc.timestamp_opt(*time as i64, 0).single().unwrap_or_default();
+                format!("at({})", time.format("%Y-%m-%dT%H:%M:%S"))
+            },
+            Timing::Rate { interval, .. } => {
+                let (count, unit) = if interval % 86400 == 0 {
+                    (interval / 86400, "day")
+                } else if interval % 3600 == 0 {
+                    (interval / 3600, "hour")
+                } else {
+                    (interval / 60, "minute")
+                };
+                format!("rate({} {}{})", count, unit, if count == 1 { "" } else { "s" })
+            }
+        }
+    }
+}
+
+/// The name of the schedule for schedule row `row_id` in `partition`. Row IDs
+/// can be longer than a schedule name, and have characters one can't.
+pub fn schedule_name(partition: &str, row_id: &str) -> String {
+    format!("selektor-{:016x}", stable_hash(format!("{}#{}", partition, row_id).as_bytes()))
+}
+
+/// One schedule row's push schedule. When it fires the target is invoked
+/// with `{"id": <row_id>}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushSchedule {
+    pub name: String,
+    pub row_id: String,
+    pub timing: Timing
+}
+
+impl PushSchedule {
+    pub fn new(partition: &str, row_id: &str, timing: Timing) -> PushSchedule {
+        PushSchedule { name: schedule_name(partition, row_id), row_id: row_id.to_string(), timing }
+    }
+
+    pub fn input(&self) -> Value {
+        json!({"id": self.row_id})
+    }
+}
+
+/// Creates and deletes push schedules: `EventBridgeScheduler` in a
+/// deployment, `LocalScheduler` in tests.
+#[async_trait]
+pub trait PushScheduler: Send + Sync {
+    /// Creates the schedule, or replaces the one with the same name.
+    async fn put(&self, schedule: &PushSchedule) -> Result<(), SchedulerError>;
+
+    /// Deletes the schedule with this name, if there is one.
+    async fn delete(&self, name: &str) -> Result<(), SchedulerError>;
+}
+
+/// Keeps schedules in memory, in place of EventBridge Scheduler.
+#[derive(Debug, Default)]
+pub struct LocalScheduler {
+    schedules: Mutex<BTreeMap<String, PushSchedule>>
+}
+
+impl LocalScheduler {
+    /// Every schedule, by name.
+    pub fn schedules(&self) -> Vec<PushSchedule> {
+        self.schedules.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[async_trait]
+impl PushScheduler for LocalScheduler {
+    async fn put(&self, schedule: &PushSchedule) -> Result<(), SchedulerError> {
+        self.schedules.lock().unwrap().insert(schedule.name.to_owned(), schedule.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), SchedulerError> {
+        self.schedules.lock().unwrap().remove(name);
+        Ok(())
+    }
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>
+}
+
+/// The parts of a request that are signed.
+struct Request<'a> {
+    method: &'a str,
+    host: &'a str,
+    path: &'a str,
+    query: &'a str,
+    content_type: Option<&'a str>,
+    body: &'a [u8]
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes everything but SigV4's unreserved characters, so that a
+/// path segment or query value is sent as it's signed.
+fn uri_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The SigV4 `Authorization` header for `request`, made at `amz_date`
+/// (`YYYYMMDDTHHMMSSZ`). Signs `host`, `x-amz-date`, and the content type and
+/// session token if there are any, which have to be sent as given.
+fn authorization(credentials: &Credentials, region: &str, service: &str, request: &Request, amz_date: &str) -> String {
+    let mut headers = vec![("host", request.host), ("x-amz-date", amz_date)];
+    if let Some(content_type) = request.content_type {
+        headers.push(("content-type", content_type));
+    }
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token));
+    }
+    headers.sort();
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method, request.path, request.query, canonical_headers, signed_headers, hex(&Sha256::digest(request.body))
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+    let key = [date, region, service, "aws4_request"].iter()
+        .fold(format!("AWS4{}", credentials.secret_access_key).into_bytes(), |key, part| hmac(&key, part));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, hex(&hmac(&key, &string_to_sign))
+    )
+}
+
+/// The push scheduler a deployment with `scheduler` uses, if any.
+pub fn for_scheduler(scheduler: &Scheduler, config: &SdkConfig) -> Result<Option<Box<dyn PushScheduler>>, String> {
+    match scheduler {
+        Scheduler::EventBridge => Ok(Some(Box::new(EventBridgeScheduler::from_env(config)?))),
+        _ => Ok(None)
+    }
+}
+
+/// Schedules pushes with EventBridge Scheduler, or anything serving its API
+/// at `SCHEDULER_ENDPOINT`. The schedules go in `SCHEDULER_GROUP`, and
+/// invoke `SCHEDULER_TARGET_ARN` as `SCHEDULER_ROLE_ARN`. The region and
+/// credentials come from the function's AWS config, as the SDK clients'
+/// do, and credentials are refreshed before they expire.
+pub struct EventBridgeScheduler {
+    client: reqwest::Client,
+    endpoint: String,
+    region: String,
+    group: String,
+    target_arn: String,
+    role_arn: String,
+    credentials: SharedCredentialsCache
+}
+
+impl EventBridgeScheduler {
+    pub fn from_env(config: &SdkConfig) -> Result<EventBridgeScheduler, String> {
+        let var = |name: &str| env::var(name).map_err(|_| format!("{} isn't set", name));
+        let region = match config.region() {
+            Some(region) => region.to_string(),
+            None => return Err("no AWS region is configured".to_string())
+        };
+        let credentials = match (config.credentials_cache(), config.credentials_provider()) {
+            (Some(cache), Some(provider)) => cache.clone().create_cache(provider.clone()),
+            _ => return Err("no AWS credentials are configured".to_string())
+        };
+        Ok(EventBridgeScheduler {
+            client: reqwest::Client::new(),
+            endpoint: env::var(SCHEDULER_ENDPOINT).unwrap_or_else(|_| format!("https://scheduler.{}.amazonaws.com", region)),
+            region,
+            group: env::var(SCHEDULER_GROUP).unwrap_or_else(|_| DEFAULT_GROUP.to_string()),
+            target_arn: var(SCHEDULER_TARGET_ARN)?,
+            role_arn: var(SCHEDULER_ROLE_ARN)?,
+            credentials
+        })
+    }
+
+    fn body(&self, schedule: &PushSchedule) -> Value {
+        let mut body = json!({
+            "ScheduleExpression": schedule.timing.expression(),
+            "FlexibleTimeWindow": {"Mode": "OFF"},
+            "GroupName": self.group,
+            "Target": {
+                "Arn": self.target_arn,
+                "RoleArn": self.role_arn,
+                "Input": schedule.input().to_string()
+            }
+        });
+        match schedule.timing {
+            Timing::At(_) => body["ActionAfterCompletion"] = json!("DELETE"),
+            Timing::Rate { start, .. } => body["StartDate"] = json!(start)
+        }
+        body
+    }
+
+    /// Sends one signed request, returning the response's status. Error
+    /// statuses other than those `allowed` fail.
+    async fn send(&self, method: &str, path: &str, query: &str, body: Option<&Value>, allowed: &[reqwest::StatusCode]) -> Result<reqwest::StatusCode, SchedulerError> {
+        let url = reqwest::Url::parse(&self.endpoint).map_err(|e| SchedulerError { reason: e.to_string() })?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(SchedulerError { reason: format!("no host in {}", self.endpoint) })
+        };
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let content_type = if body.is_empty() { None } else { Some("application/json") };
+        let credentials = self.credentials.provide_cached_credentials().await
+            .map_err(|e| SchedulerError { reason: format!("couldn't get credentials: {}", e) })?;
+        let credentials = Credentials {
+            access_key_id: credentials.access_key_id().to_string(),
+            secret_access_key: credentials.secret_access_key().to_string(),
+            session_token: credentials.session_token().map(str::to_string)
+        };
+        let now: DateTime<Utc> = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let request = Request { method, host: &host, path, query, content_type, body: body.as_bytes() };
+        let mut builder = self.client
+            .request(reqwest::Method::from_bytes(method.as_bytes()).map_err(|e| SchedulerError { reason: e.to_string() })?,
+                format!("{}{}{}{}", self.endpoint.trim_end_matches('/'), path, if query.is_empty() { "" } else { "?" }, query))
+            .header("host", &host)
+            .header("x-amz-date", &amz_date)
+            .header("authorization", authorization(&credentials, &self.region, SERVICE, &request, &amz_date));
+        if let Some(content_type) = content_type {
+            builder = builder.header("content-type", content_type);
+        }
+        if let Some(token) = &credentials.session_token {
+            builder = builder.header("x-amz-security-token", token);
+        }
+        let response = builder.body(body).send().await?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            let text = response.text().await.unwrap_or_default();
+            if !allowed.contains(&status) {
+                return Err(SchedulerError { reason: format!("{} {} failed with {}: {}", method, path, status, text) })
+            }
+        }
+        Ok(status)
+    }
+}
+
+#[async_trait]
+impl PushScheduler for EventBridgeScheduler {
+    async fn put(&self, schedule: &PushSchedule) -> Result<(), SchedulerError> {
+        let path = format!("/schedules/{}", uri_encode(&schedule.name));
+        let body = self.body(schedule);
+        // CreateSchedule, or UpdateSchedule if it's already there.
+        if self.send("POST", &path, "", Some(&body), &[reqwest::StatusCode::CONFLICT]).await? == reqwest::StatusCode::CONFLICT {
+            self.send("PUT", &path, "", Some(&body), &[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), SchedulerError> {
+        // A schedule that's already gone comes back as a 404.
+        let path = format!("/schedules/{}", uri_encode(name));
+        let query = format!("groupName={}", uri_encode(&self.group));
+        self.send("DELETE", &path, &query, None, &[reqwest::StatusCode::NOT_FOUND]).await?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_timing() {
+    assert_eq!(Timing::new(1_684_000_000, Some(300)), Timing::Rate { start: 1_684_000_000, interval: 300 });
+    assert_eq!(Timing::new(1_684_000_000, Some(90)), Timing::At(1_684_000_000));
+    assert_eq!(Timing::new(1_684_000_000, None), Timing::At(1_684_000_000));
+    assert_eq!(Timing::At(1_684_000_000).expression(), "at(2023-05-13T17:46:40)");
+    assert_eq!(Timing::Rate { start: 0, interval: 300 }.expression(), "rate(5 minutes)");
+    assert_eq!(Timing::Rate { start: 0, interval: 3600 }.expression(), "rate(1 hour)");
+    assert_eq!(Timing::Rate { start: 0, interval: 2 * 86400 }.expression(), "rate(2 days)");
+    assert_eq!(schedule_name("default", "p#a").len(), 25);
+}
+
+#[test]
+fn test_uri_encode() {
+    assert_eq!(uri_encode("selektor-0123abcd"), "selektor-0123abcd");
+    assert_eq!(uri_encode("my group/1+a~b"), "my%20group%2F1%2Ba~b");
+}
+
+#[test]
+fn test_local_scheduler() {
+    let scheduler = LocalScheduler::default();
+    let first = PushSchedule::new("default", "p#a", Timing::At(1_684_000_000));
+    let moved = PushSchedule { timing: Timing::At(1_684_000_300), ..first.clone() };
+    let other = PushSchedule::new("default", "p#b", Timing::Rate { start: 1_684_000_000, interval: 300 });
+    futures::executor::block_on(async {
+        scheduler.put(&first).await.unwrap();
+        scheduler.put(&other).await.unwrap();
+        scheduler.put(&moved).await.unwrap();
+        assert_eq!(scheduler.schedules().len(), 2);
+        assert!(scheduler.schedules().contains(&moved));
+        scheduler.delete(&other.name).await.unwrap();
+        scheduler.delete(&other.name).await.unwrap();
+        assert_eq!(scheduler.schedules(), vec![moved.clone()]);
+    });
+    assert_eq!(moved.input(), json!({"id": "p#a"}));
+}
+
+#[test]
+fn test_authorization() {
+    // The example from the AWS General Reference's Signature Version 4 test suite.
+    let credentials = Credentials {
+        access_key_id: "AKIDEXAMPLE".to_string(),
+        secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+        session_token: None
+    };
+    let request = Request {
+        method: "GET",
+        host: "iam.amazonaws.com",
+        path: "/",
+        query: "Action=ListUsers&Version=2010-05-08",
+        content_type: Some("application/x-www-form-urlencoded; charset=utf-8"),
+        body: b""
+    };
+    assert_eq!(
+        authorization(&credentials, "us-east-1", "iam", &request, "20150830T123600Z"),
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+         SignedHeaders=content-type;host;x-amz-date, \
+         Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+    );
+}
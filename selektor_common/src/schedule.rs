@@ -0,0 +1,604 @@
+use crate::local_time::{instant, resolve};
+use chrono::{Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+pub const TICK_SECONDS: &str = "TICK_SECONDS";
+pub const SCHEDULE_JITTER_SECONDS: &str = "SCHEDULE_JITTER_SECONDS";
+pub const SCHEDULE_SHARDS: &str = "SCHEDULE_SHARDS";
+pub const SCHEDULER: &str = "SCHEDULER";
+pub const DUE_QUEUE_TABLE_NAME: &str = "DUE_QUEUE_TABLE_NAME";
+const DEFAULT_TICK_SECONDS: u64 = 5 * 60;
+const DEFAULT_SHARDS: u32 = 16;
+/// Schedule times used to be counted in 5 minute buckets since the epoch.
+pub const LEGACY_BUCKET_SECONDS: u64 = 5 * 60;
+/// Times below this are legacy bucket numbers. As epoch seconds it's in 2001;
+/// as buckets it's some 9,000 years off.
+const LEGACY_CUTOFF: u64 = 1_000_000_000;
+/// Catching up a cron schedule stops counting missed occurrences after this
+/// many, and moves straight on to the next one after now.
+const MAX_CRON_CATCH_UP: u64 = 10_000;
+/// Due queue entries are kept this long past their tick, via the table's TTL.
+pub const QUEUE_RETENTION_SECONDS: u64 = 24 * 60 * 60;
+
+/// Whether a schedule time is a legacy bucket number rather than epoch seconds.
+pub fn is_legacy_bucket(time: u64) -> bool {
+    time < LEGACY_CUTOFF
+}
+
+/// How often `run_notify` runs, and so the resolution schedules fire at. It's
+/// a deployment setting, `TICK_SECONDS`, which has to match the rule invoking
+/// `run_notify`. Schedule times themselves are epoch seconds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tick(u64);
+
+impl Tick {
+    pub fn new(seconds: u64) -> Tick {
+        Tick(seconds.max(1))
+    }
+
+    pub fn from_env() -> Result<Tick, String> {
+        match env::var(TICK_SECONDS) {
+            Ok(seconds) => u64::from_str(&seconds).map(Tick::new)
+                .map_err(|_| format!("invalid {}: {}", TICK_SECONDS, seconds)),
+            Err(_) => Ok(Tick::default())
+        }
+    }
+
+    pub fn seconds(&self) -> u64 {
+        self.0
+    }
+
+    /// The start of the tick `time` is in.
+    pub fn start(&self, time: u64) -> u64 {
+        time - time % self.0
+    }
+
+    /// The start of the tick after the one `time` is in.
+    pub fn next(&self, time: u64) -> u64 {
+        self.start(time) + self.0
+    }
+
+    /// The tick whose due queue bucket a schedule firing at `time` goes in,
+    /// as of `now`: the tick `time` is in, unless that's `now`'s or earlier,
+    /// which may already have been read, in which case the one after `now`'s.
+    pub fn queue_bucket(&self, time: u64, now: u64) -> u64 {
+        self.start(time).max(self.next(now))
+    }
+
+    /// The ticks, up to and including `current`'s, whose due queue buckets
+    /// are still to be read, when every one up to `swept`'s has been. Ticks
+    /// whose entries would have expired are left out, and without a `swept`
+    /// only `current`'s is read.
+    pub fn unread(&self, swept: Option<u64>, current: u64) -> Vec<u64> {
+        let current = self.start(current);
+        let first = match swept {
+            Some(swept) => self.next(swept).max(self.start(current.saturating_sub(QUEUE_RETENTION_SECONDS))),
+            None => current
+        };
+        (first..=current).step_by(self.0 as usize).collect()
+    }
+}
+
+impl Default for Tick {
+    fn default() -> Self {
+        Tick(DEFAULT_TICK_SECONDS)
+    }
+}
+
+/// 64-bit FNV-1a, for hashes that have to stay the same across releases and
+/// between the lambdas, which std's hasher doesn't promise.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Spreads a partition's schedules over this many keys of the due index, so
+/// that the tick's reads and everyone's writes don't all land on one. A row's
+/// shard is worked out again whenever it's written, so rows move as they're
+/// rewritten after the count changes. The count can be raised, since every
+/// shard below it is read, but not lowered without rewriting the rows in the
+/// shards dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shards(u32);
+
+impl Default for Shards {
+    fn default() -> Self {
+        Shards(DEFAULT_SHARDS)
+    }
+}
+
+impl Shards {
+    pub fn new(count: u32) -> Shards {
+        Shards(count.max(1))
+    }
+
+    pub fn from_env() -> Result<Shards, String> {
+        match env::var(SCHEDULE_SHARDS) {
+            Ok(count) => u32::from_str(&count).map(Shards::new)
+                .map_err(|_| format!("invalid {}: {}", SCHEDULE_SHARDS, count)),
+            Err(_) => Ok(Shards::default())
+        }
+    }
+
+    /// The shard key for schedule row `id` in `partition`.
+    pub fn key(&self, partition: &str, id: &str) -> String {
+        shard_key(partition, stable_hash(id.as_bytes()) % self.0 as u64)
+    }
+
+    /// Every shard key in `partition`.
+    pub fn keys(&self, partition: &str) -> Vec<String> {
+        (0..self.0 as u64).map(|shard| shard_key(partition, shard)).collect()
+    }
+}
+
+fn shard_key(partition: &str, shard: u64) -> String {
+    format!("{}#shard#{}", partition, shard)
+}
+
+/// The schedules table's key for a principal's schedule: IDs only have to be
+/// unique to each principal.
+pub fn row_key(principal: &str, id: &str) -> String {
+    format!("{}#{}", principal, id)
+}
+
+/// The key of the row holding the principal's schedule version. Schedule IDs
+/// can't be empty, so it can't be taken by a schedule.
+pub fn version_key(principal: &str) -> String {
+    row_key(principal, "")
+}
+
+/// The due queue key for the schedules in `shard` due in the tick starting
+/// at `bucket`.
+pub fn queue_key(shard: &str, bucket: u64) -> String {
+    format!("{}#{}", shard, bucket)
+}
+
+/// The key of the due queue row recording the last tick of `partition`
+/// whose buckets were all read.
+pub fn swept_key(partition: &str) -> String {
+    format!("{}#swept", partition)
+}
+
+/// How `run_notify` finds the schedules due in a tick. It's a deployment
+/// setting, `SCHEDULER`, which `update_schedule` reads too.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Scheduler {
+    /// Query the due index for everything with a `next_fire` before the end
+    /// of the tick, which includes anything overdue.
+    #[default]
+    Index,
+    /// Read the tick's own bucket of the due queue table, named by
+    /// `DUE_QUEUE_TABLE_NAME`. Each schedule has an entry in the bucket for
+    /// its `next_fire`, written along with it.
+    Queue { table_name: String },
+    /// Give every schedule its own EventBridge schedule, which invokes the
+    /// `deliver` function when it's due; see `push_scheduler`. Ticks, if the
+    /// rule's left on, query the due index for anything missed.
+    EventBridge
+}
+
+impl Scheduler {
+    pub fn from_env() -> Result<Scheduler, String> {
+        match env::var(SCHEDULER).as_deref() {
+            Err(_) | Ok("index") => Ok(Scheduler::Index),
+            Ok("queue") => env::var(DUE_QUEUE_TABLE_NAME)
+                .map(|table_name| Scheduler::Queue { table_name })
+                .map_err(|_| format!("{} is queue, but {} isn't set", SCHEDULER, DUE_QUEUE_TABLE_NAME)),
+            Ok("eventbridge") => Ok(Scheduler::EventBridge),
+            Ok(scheduler) => Err(format!("invalid {}: {}", SCHEDULER, scheduler))
+        }
+    }
+
+    /// The due queue table, if schedules are queued.
+    pub fn queue_table(&self) -> Option<&str> {
+        match self {
+            Scheduler::Index | Scheduler::EventBridge => None,
+            Scheduler::Queue { table_name } => Some(table_name)
+        }
+    }
+}
+
+/// Spreads schedules that would otherwise fire together over a window, by
+/// offsetting each one by an amount derived from its ID. The window is a
+/// deployment setting, `SCHEDULE_JITTER_SECONDS`; without it there's no jitter.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Jitter(u64);
+
+impl Jitter {
+    pub fn new(window: u64) -> Jitter {
+        Jitter(window)
+    }
+
+    pub fn from_env() -> Result<Jitter, String> {
+        match env::var(SCHEDULE_JITTER_SECONDS) {
+            Ok(window) => u64::from_str(&window).map(Jitter)
+                .map_err(|_| format!("invalid {}: {}", SCHEDULE_JITTER_SECONDS, window)),
+            Err(_) => Ok(Jitter::default())
+        }
+    }
+
+    /// The offset for schedule `id`, in `[0, window)` seconds.
+    pub fn offset(&self, id: &str) -> u64 {
+        if self.0 == 0 {
+            return 0
+        }
+        stable_hash(id.as_bytes()) % self.0
+    }
+}
+
+/// A cron expression in the usual five fields: minute, hour, day of month,
+/// month and day of week, evaluated in UTC unless given a time zone. Days of
+/// the week have to be given by name (`Mon-Fri`), since cron implementations
+/// disagree on whether Sunday is 0 or 1.
+#[derive(Clone, Debug)]
+pub struct CronSpec {
+    expression: String,
+    schedule: Box<cron::Schedule>,
+    time_zone: Tz,
+    /// Seconds every occurrence is moved later by.
+    offset: u64
+}
+
+impl CronSpec {
+    pub fn parse(expression: &str) -> Result<CronSpec, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron expression '{}' should have 5 fields, not {}", expression, fields.len()))
+        }
+        let numeric_weekday = fields[4].split(',')
+            .any(|item| item.split('/').next().unwrap_or_default().contains(|c: char| c.is_ascii_digit()));
+        if numeric_weekday {
+            return Err(format!("cron expression '{}' should name its days of the week, e.g. Mon-Fri", expression))
+        }
+        // The cron crate wants a seconds field too.
+        let schedule = cron::Schedule::from_str(&format!("0 {}", fields.join(" ")))
+            .map_err(|e| format!("invalid cron expression '{}': {}", expression, e))?;
+        let spec = CronSpec { expression: fields.join(" "), schedule: Box::new(schedule), time_zone: Tz::UTC, offset: 0 };
+        if spec.next_at_or_after(Utc::now().timestamp().max(0) as u64).is_none() {
+            return Err(format!("cron expression '{}' never fires", expression))
+        }
+        Ok(spec)
+    }
+
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    /// Evaluates the expression on the wall clock in `time_zone`, so "daily
+    /// at 8" stays at 8 across DST changes.
+    pub fn in_time_zone(self, time_zone: Tz) -> CronSpec {
+        CronSpec { time_zone, ..self }
+    }
+
+    /// Moves every occurrence `offset` seconds later, e.g. by its `Jitter`.
+    pub fn with_offset(self, offset: u64) -> CronSpec {
+        CronSpec { offset, ..self }
+    }
+
+    /// The first occurrence at or after `time`, in epoch seconds.
+    pub fn next_at_or_after(&self, time: u64) -> Option<u64> {
+        let start = instant(time.saturating_sub(self.offset))?;
+        // The cron crate doesn't handle DST, so occurrences are found on a
+        // naive local clock and pinned to instants with `resolve`. Starting a
+        // few hours early covers any offset change around `start`.
+        let local_start = start.with_timezone(&self.time_zone).naive_local() - Duration::hours(3);
+        self.schedule.after(&Utc.from_utc_datetime(&local_start))
+            .filter_map(|t| resolve(self.time_zone, t.naive_utc()))
+            .find(|t| *t >= start)
+            .map(|t| t.timestamp().max(0) as u64 + self.offset)
+    }
+}
+
+impl PartialEq for CronSpec {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression && self.time_zone == other.time_zone && self.offset == other.offset
+    }
+}
+
+impl Eq for CronSpec {}
+
+/// How a schedule repeats.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Recurrence {
+    /// Every this many seconds.
+    Interval(u64),
+    Cron(CronSpec)
+}
+
+impl Recurrence {
+    /// When to fire after firing at `time`, or `None` if it never fires
+    /// again. Cron occurrences in the same tick as `time` are passed over, so
+    /// a cron schedule fires at most once a tick.
+    pub fn next_after(&self, time: u64, tick: Tick) -> Option<u64> {
+        match self {
+            Recurrence::Interval(interval) => Some(time + (*interval).max(1)),
+            Recurrence::Cron(spec) => spec.next_at_or_after(tick.next(time))
+        }
+    }
+
+    /// How many occurrences from `next_fire` are before `end`, the latest of
+    /// them, and the first one at or after `end`.
+    fn due(&self, next_fire: u64, end: u64, tick: Tick) -> (u64, Option<u64>, Option<u64>) {
+        if next_fire >= end {
+            return (0, None, Some(next_fire))
+        }
+        match self {
+            Recurrence::Interval(interval) => {
+                let interval = (*interval).max(1);
+                let due = (end - 1 - next_fire) / interval + 1;
+                (due, Some(next_fire + (due - 1) * interval), Some(next_fire + due * interval))
+            },
+            Recurrence::Cron(spec) => {
+                let mut due = 0;
+                let mut latest = None;
+                let mut next = Some(next_fire);
+                while let Some(time) = next.filter(|t| *t < end) {
+                    if due == MAX_CRON_CATCH_UP {
+                        return (due, None, spec.next_at_or_after(end))
+                    }
+                    due += 1;
+                    latest = Some(time);
+                    next = self.next_after(time, tick);
+                }
+                (due, latest, next)
+            }
+        }
+    }
+}
+
+/// What to do with the occurrences of a schedule that came due while nothing
+/// was firing it, e.g. during an outage.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUpPolicy {
+    /// Drop missed occurrences; only fire when the latest one is on time.
+    Skip,
+    /// Fire once for however many occurrences were missed.
+    #[default]
+    Once,
+    /// Fire each missed occurrence, up to this many; at least one, so it
+    /// can't drop an on-time occurrence.
+    UpTo(NonZeroU32)
+}
+
+/// How a due schedule should be fired.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CatchUp {
+    /// How many pushes to send now.
+    pub fires: u64,
+    /// How many occurrences are dropped rather than sent.
+    pub missed: u64,
+    /// The next occurrence after this tick, keeping the schedule's phase, or
+    /// `None` if the schedule never fires again.
+    pub next_fire: Option<u64>
+}
+
+impl CatchUpPolicy {
+    /// Works out what to send for a schedule in the tick `now` is in, all
+    /// times in epoch seconds. Everything due before the end of that tick is
+    /// fired or dropped, and an occurrence is on time if it's in that tick. A
+    /// schedule that isn't due yet is left where it is.
+    pub fn apply(&self, next_fire: u64, recurrence: &Recurrence, now: u64, tick: Tick) -> CatchUp {
+        let (due, latest, next_fire) = recurrence.due(next_fire, tick.next(now), tick);
+        let on_time = latest.is_some_and(|latest| latest >= tick.start(now));
+        let fires = match self {
+            CatchUpPolicy::Skip => if on_time { 1 } else { 0 },
+            CatchUpPolicy::Once => due.min(1),
+            CatchUpPolicy::UpTo(limit) => due.min(limit.get() as u64)
+        };
+        CatchUp { fires, missed: due - fires, next_fire }
+    }
+
+    /// Like `apply`, for a push held by quiet hours since the occurrence at
+    /// `held`. It goes out in place of everything that came due since, and
+    /// the schedule carries on from `held`, as if that had fired on time.
+    pub fn apply_held(&self, held: u64, recurrence: &Recurrence, now: u64, tick: Tick) -> CatchUp {
+        match self.apply(held, recurrence, now, tick) {
+            CatchUp { fires: 0, missed, next_fire } if missed > 0 => CatchUp { fires: 1, missed: missed - 1, next_fire },
+            catch_up => catch_up
+        }
+    }
+}
+
+impl std::fmt::Display for CatchUpPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatchUpPolicy::Skip => write!(f, "skip"),
+            CatchUpPolicy::Once => write!(f, "once"),
+            CatchUpPolicy::UpTo(limit) => write!(f, "up_to:{}", limit)
+        }
+    }
+}
+
+/// Parses the form stored on schedule items, as written by `Display`.
+impl FromStr for CatchUpPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(CatchUpPolicy::Skip),
+            "once" => Ok(CatchUpPolicy::Once),
+            _ => match s.strip_prefix("up_to:").map(NonZeroU32::from_str) {
+                Some(Ok(limit)) => Ok(CatchUpPolicy::UpTo(limit)),
+                _ => Err(format!("invalid catch-up policy: {}", s))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_on_time() {
+    for policy in [CatchUpPolicy::Skip, CatchUpPolicy::Once, CatchUpPolicy::UpTo(NonZeroU32::new(3).unwrap())] {
+        assert_eq!(policy.apply(100, &Recurrence::Interval(12), 100, Tick::new(1)), CatchUp { fires: 1, missed: 0, next_fire: Some(112) });
+    }
+}
+
+#[test]
+fn test_missed_occurrences() {
+    // Due at 100, 112, 124 and 136; now is 140, so the latest is 4 ticks late.
+    assert_eq!(CatchUpPolicy::Skip.apply(100, &Recurrence::Interval(12), 140, Tick::new(1)), CatchUp { fires: 0, missed: 4, next_fire: Some(148) });
+    assert_eq!(CatchUpPolicy::Once.apply(100, &Recurrence::Interval(12), 140, Tick::new(1)), CatchUp { fires: 1, missed: 3, next_fire: Some(148) });
+    assert_eq!(CatchUpPolicy::UpTo(NonZeroU32::new(2).unwrap()).apply(100, &Recurrence::Interval(12), 140, Tick::new(1)), CatchUp { fires: 2, missed: 2, next_fire: Some(148) });
+    assert_eq!(CatchUpPolicy::UpTo(NonZeroU32::new(10).unwrap()).apply(100, &Recurrence::Interval(12), 140, Tick::new(1)), CatchUp { fires: 4, missed: 0, next_fire: Some(148) });
+    // The latest missed occurrence landing on now still counts as on time.
+    assert_eq!(CatchUpPolicy::Skip.apply(100, &Recurrence::Interval(12), 136, Tick::new(1)), CatchUp { fires: 1, missed: 3, next_fire: Some(148) });
+}
+
+#[test]
+fn test_round_trip() {
+    for policy in [CatchUpPolicy::Skip, CatchUpPolicy::Once, CatchUpPolicy::UpTo(NonZeroU32::new(5).unwrap())] {
+        assert_eq!(CatchUpPolicy::from_str(&policy.to_string()), Ok(policy));
+    }
+    assert_eq!(serde_json::from_str::<CatchUpPolicy>("{\"up_to\":5}").unwrap(), CatchUpPolicy::UpTo(NonZeroU32::new(5).unwrap()));
+    assert!(CatchUpPolicy::from_str("sometimes").is_err());
+    // Up to none would drop on-time occurrences too.
+    assert!(CatchUpPolicy::from_str("up_to:0").is_err());
+    assert!(serde_json::from_str::<CatchUpPolicy>("{\"up_to\":0}").is_err());
+}
+
+#[test]
+fn test_apply_held() {
+    // Held at 100 until 130; the 112 and 124 occurrences were held too.
+    assert_eq!(CatchUpPolicy::Once.apply_held(100, &Recurrence::Interval(12), 130, Tick::new(1)), CatchUp { fires: 1, missed: 2, next_fire: Some(136) });
+    // Skip still sends the held push, though nothing is on time.
+    assert_eq!(CatchUpPolicy::Skip.apply_held(100, &Recurrence::Interval(12), 130, Tick::new(1)), CatchUp { fires: 1, missed: 2, next_fire: Some(136) });
+    // Released before the next occurrence: the cadence is unchanged.
+    assert_eq!(CatchUpPolicy::Once.apply_held(100, &Recurrence::Interval(12), 105, Tick::new(1)), CatchUp { fires: 1, missed: 0, next_fire: Some(112) });
+}
+
+#[test]
+fn test_not_due() {
+    assert_eq!(CatchUpPolicy::Once.apply(100, &Recurrence::Interval(12), 99, Tick::new(1)), CatchUp { fires: 0, missed: 0, next_fire: Some(100) });
+}
+
+#[cfg(test)]
+fn secs(rfc3339: &str) -> u64 {
+    chrono::DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp() as u64
+}
+
+#[test]
+fn test_tick() {
+    let tick = Tick::new(300);
+    assert_eq!(tick.start(1_200_150), 1_200_000);
+    assert_eq!(tick.next(1_200_150), 1_200_300);
+    assert_eq!(tick.next(1_200_000), 1_200_300);
+    // Fires that aren't on a tick boundary are due in the tick they fall in.
+    let hourly = Recurrence::Interval(3600);
+    assert_eq!(CatchUpPolicy::Skip.apply(1_200_250, &hourly, 1_200_150, tick), CatchUp { fires: 1, missed: 0, next_fire: Some(1_203_850) });
+    assert_eq!(CatchUpPolicy::Skip.apply(1_200_300, &hourly, 1_200_150, tick), CatchUp { fires: 0, missed: 0, next_fire: Some(1_200_300) });
+    assert!(is_legacy_bucket(5_612_345));
+    assert!(!is_legacy_bucket(secs("2023-03-03T09:00:00Z")));
+}
+
+#[test]
+fn test_cron_next() {
+    let tick = Tick::default();
+    let weekdays = Recurrence::Cron(CronSpec::parse("0 9 * * Mon-Fri").unwrap());
+    // Friday at 9:00, then Monday at 9:00.
+    let friday = secs("2023-03-03T09:00:00Z");
+    assert_eq!(weekdays.next_after(friday - 3600, tick), Some(friday));
+    assert_eq!(weekdays.next_after(friday, tick), Some(secs("2023-03-06T09:00:00Z")));
+    // Every minute still fires once a tick.
+    let minutely = Recurrence::Cron(CronSpec::parse("* * * * *").unwrap());
+    assert_eq!(minutely.next_after(friday, tick), Some(friday + 300));
+}
+
+#[test]
+fn test_cron_parse() {
+    assert!(CronSpec::parse("0 8-20 * * *").is_ok());
+    assert!(CronSpec::parse("*/15 * * * Sat,Sun").is_ok());
+    assert!(CronSpec::parse("0 9 * * 1-5").is_err());
+    assert!(CronSpec::parse("0 9 * *").is_err());
+    assert!(CronSpec::parse("0 25 * * *").is_err());
+    assert!(CronSpec::parse("0 0 30 2 *").is_err());
+}
+
+#[test]
+fn test_cron_catch_up() {
+    let tick = Tick::default();
+    let hourly = Recurrence::Cron(CronSpec::parse("0 * * * *").unwrap());
+    let start = secs("2023-03-03T09:00:00Z");
+    let ten_past_eleven = secs("2023-03-03T11:10:00Z");
+    let noon = Some(secs("2023-03-03T12:00:00Z"));
+    // Due at 9, 10 and 11.
+    assert_eq!(CatchUpPolicy::UpTo(NonZeroU32::new(5).unwrap()).apply(start, &hourly, ten_past_eleven, tick), CatchUp { fires: 3, missed: 0, next_fire: noon });
+    assert_eq!(CatchUpPolicy::Skip.apply(start, &hourly, ten_past_eleven, tick), CatchUp { fires: 0, missed: 3, next_fire: noon });
+    assert_eq!(CatchUpPolicy::Skip.apply(start, &hourly, secs("2023-03-03T11:02:00Z"), tick), CatchUp { fires: 1, missed: 2, next_fire: noon });
+}
+
+#[test]
+fn test_cron_across_dst() {
+    let new_york = crate::local_time::parse_time_zone("America/New_York").unwrap();
+    let daily = CronSpec::parse("0 8 * * *").unwrap().in_time_zone(new_york);
+    // 08:00 is 13:00 UTC before the clocks go forward on 2023-03-12, and 12:00 after.
+    let saturday = secs("2023-03-11T13:00:00Z");
+    assert_eq!(daily.next_at_or_after(saturday), Some(saturday));
+    assert_eq!(daily.next_at_or_after(saturday + 1), Some(secs("2023-03-12T12:00:00Z")));
+    // 02:30 doesn't happen that night, so it fires at 03:30 instead.
+    let early = CronSpec::parse("30 2 * * *").unwrap().in_time_zone(new_york);
+    assert_eq!(early.next_at_or_after(secs("2023-03-11T12:00:00Z")), Some(secs("2023-03-12T07:30:00Z")));
+    // 01:30 happens twice when they go back on 2023-11-05; it fires the first time only.
+    let late = CronSpec::parse("30 1 * * *").unwrap().in_time_zone(new_york);
+    let first = secs("2023-11-05T05:30:00Z");
+    assert_eq!(late.next_at_or_after(first), Some(first));
+    assert_eq!(late.next_at_or_after(first + 1), Some(secs("2023-11-06T06:30:00Z")));
+}
+
+#[test]
+fn test_jitter() {
+    let jitter = Jitter::new(600);
+    assert_eq!(Jitter::default().offset("abc"), 0);
+    // The same ID always gets the same offset, within the window.
+    assert_eq!(jitter.offset("abc"), jitter.offset("abc"));
+    assert!((0..100).map(|i| jitter.offset(&format!("id-{}", i))).all(|offset| offset < 600));
+    assert_ne!(jitter.offset("id-1"), jitter.offset("id-2"));
+    // Cron occurrences all move by the offset, and advance from the moved time.
+    let tick = Tick::default();
+    let daily = Recurrence::Cron(CronSpec::parse("0 9 * * *").unwrap().with_offset(420));
+    let nine = secs("2023-03-03T09:00:00Z");
+    assert_eq!(daily.next_after(nine - 3600, tick), Some(nine + 420));
+    assert_eq!(daily.next_after(nine + 420, tick), Some(secs("2023-03-04T09:07:00Z")));
+}
+
+#[test]
+fn test_shards() {
+    let shards = Shards::new(4);
+    assert_eq!(shards.keys("default"), vec!["default#shard#0", "default#shard#1", "default#shard#2", "default#shard#3"]);
+    assert_eq!(shards.key("default", "p#a"), shards.key("default", "p#a"));
+    assert!((0..100).all(|i| shards.keys("default").contains(&shards.key("default", &format!("p#{}", i)))));
+    // Raising the count only adds shards, so rows written before are still read.
+    assert!(Shards::new(8).keys("default").starts_with(&shards.keys("default")));
+    assert_eq!(Shards::new(0).keys("default"), vec!["default#shard#0"]);
+}
+
+#[test]
+fn test_queue_bucket() {
+    let tick = Tick::new(300);
+    let now = 1_684_000_100;
+    // Due in a later tick: that tick's bucket.
+    assert_eq!(tick.queue_bucket(1_684_000_650, now), 1_684_000_500);
+    assert_eq!(tick.queue_bucket(1_684_000_200, now), 1_684_000_200);
+    // Due in this tick or overdue: the next tick's, since this one may have
+    // been read already.
+    assert_eq!(tick.queue_bucket(1_684_000_000, now), 1_684_000_200);
+    assert_eq!(tick.queue_bucket(1_683_000_000, now), 1_684_000_200);
+    assert_eq!(queue_key("default#shard#3", 1_684_000_200), "default#shard#3#1684000200");
+}
+
+#[test]
+fn test_unread() {
+    let tick = Tick::new(300);
+    let now = 1_684_000_200;
+    assert_eq!(tick.unread(None, now), vec![now]);
+    assert_eq!(tick.unread(Some(now - 300), now), vec![now]);
+    // Two ticks that didn't run.
+    assert_eq!(tick.unread(Some(now - 900), now), vec![now - 600, now - 300, now]);
+    // Read already, by an invocation for the same tick.
+    assert!(tick.unread(Some(now), now).is_empty());
+    // Not back past when entries expire.
+    assert_eq!(tick.unread(Some(0), now).len() as u64, QUEUE_RETENTION_SECONDS / 300 + 1);
+    assert_eq!(swept_key("default"), "default#swept");
+}
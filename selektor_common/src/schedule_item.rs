@@ -0,0 +1,106 @@
+use crate::local_time::{parse_time_zone, QuietHours};
+use crate::schedule::{CatchUpPolicy, CronSpec, Recurrence};
+use aws_sdk_dynamodb::model::AttributeValue;
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A schedule row as it's stored in the schedules table. Each field reads as
+/// what a row without it means, and one that can't be read is an error for
+/// the caller to report.
+pub type Item = HashMap<String, AttributeValue>;
+
+/// The schedule's catch-up policy; schedules without one fire once.
+pub fn catch_up(item: &Item) -> Result<CatchUpPolicy, String> {
+    match item.get("catch_up") {
+        Some(AttributeValue::S(policy)) => CatchUpPolicy::from_str(policy),
+        _ => Ok(CatchUpPolicy::default())
+    }
+}
+
+/// The schedule's time zone, UTC if it has none.
+pub fn time_zone(item: &Item) -> Result<Tz, String> {
+    match item.get("time_zone") {
+        Some(AttributeValue::S(name)) => parse_time_zone(name),
+        _ => Ok(Tz::UTC)
+    }
+}
+
+/// The schedule's quiet hours, stored as JSON.
+pub fn quiet_hours(item: &Item) -> Result<Vec<QuietHours>, String> {
+    match item.get("quiet_hours") {
+        Some(AttributeValue::S(json)) => serde_json::from_str(json)
+            .map_err(|e| format!("invalid quiet hours {}: {}", json, e)),
+        _ => Ok(Vec::new())
+    }
+}
+
+/// How the schedule repeats: its cron expression if it has one, otherwise
+/// its `fire_interval`, or `None` with neither. Cron occurrences are moved
+/// by the schedule's jitter `offset`; an interval schedule's `next_fire`
+/// already has it. A time zone that can't be read is taken to be UTC.
+pub fn recurrence(item: &Item, offset: u64) -> Result<Option<Recurrence>, String> {
+    match (item.get("cron"), item.get("fire_interval")) {
+        (Some(AttributeValue::S(expression)), _) => CronSpec::parse(expression)
+            .map(|spec| Some(Recurrence::Cron(spec.in_time_zone(time_zone(item).unwrap_or(Tz::UTC)).with_offset(offset)))),
+        (_, Some(AttributeValue::N(interval))) => u64::from_str(interval).map(|interval| Some(Recurrence::Interval(interval)))
+            .map_err(|e| format!("couldn't parse fire_interval {}: {}", interval, e)),
+        _ => Ok(None)
+    }
+}
+
+/// How the schedule's EventBridge schedule fires when it's next due at
+/// `next_fire`. Quiet hours that can't be read are ignored, as they are
+/// when it fires.
+#[cfg(feature = "eventbridge")]
+pub fn push_timing(item: &Item, next_fire: u64) -> crate::push_scheduler::Timing {
+    let fire_interval = match item.get("fire_interval") {
+        Some(AttributeValue::N(interval)) => u64::from_str(interval).ok(),
+        _ => None
+    };
+    crate::push_scheduler::Timing::for_schedule(next_fire, fire_interval, item.contains_key("cron"), &quiet_hours(item).unwrap_or_default())
+}
+
+#[test]
+fn test_decode() {
+    let mut item = Item::from([
+        (String::from("fire_interval"), AttributeValue::N(String::from("3600")))
+    ]);
+    assert_eq!(catch_up(&item), Ok(CatchUpPolicy::Once));
+    assert_eq!(time_zone(&item), Ok(Tz::UTC));
+    assert_eq!(quiet_hours(&item), Ok(Vec::new()));
+    assert_eq!(recurrence(&item, 30), Ok(Some(Recurrence::Interval(3600))));
+
+    item.insert(String::from("catch_up"), AttributeValue::S(String::from("skip")));
+    item.insert(String::from("time_zone"), AttributeValue::S(String::from("Europe/Berlin")));
+    item.insert(String::from("cron"), AttributeValue::S(String::from("0 9 * * *")));
+    assert_eq!(catch_up(&item), Ok(CatchUpPolicy::Skip));
+    assert_eq!(time_zone(&item), Ok(Tz::Europe__Berlin));
+    let spec = CronSpec::parse("0 9 * * *").unwrap().in_time_zone(Tz::Europe__Berlin).with_offset(30);
+    assert_eq!(recurrence(&item, 30), Ok(Some(Recurrence::Cron(spec))));
+
+    item.insert(String::from("quiet_hours"), AttributeValue::S(String::from("[{\"start\": 7}]")));
+    assert!(quiet_hours(&item).is_err());
+    item.insert(String::from("catch_up"), AttributeValue::S(String::from("sometimes")));
+    assert!(catch_up(&item).is_err());
+    assert_eq!(recurrence(&Item::new(), 0), Ok(None));
+}
+
+#[cfg(feature = "eventbridge")]
+#[test]
+fn test_push_timing() {
+    use crate::push_scheduler::Timing;
+    let mut item = Item::from([
+        (String::from("fire_interval"), AttributeValue::N(String::from("3600")))
+    ]);
+    assert_eq!(push_timing(&item, 1_700_000_000), Timing::Rate { start: 1_700_000_000, interval: 3_600 });
+    // No quiet hours is the same as none stored.
+    item.insert(String::from("quiet_hours"), AttributeValue::S(String::from("[]")));
+    assert_eq!(push_timing(&item, 1_700_000_000), Timing::Rate { start: 1_700_000_000, interval: 3_600 });
+    // Quiet hours move occurrences, so they're scheduled one at a time.
+    item.insert(String::from("quiet_hours"), AttributeValue::S(String::from("[{\"start\": \"22:00\", \"end\": \"07:00\"}]")));
+    assert_eq!(push_timing(&item, 1_700_000_000), Timing::At(1_700_000_000));
+    item.remove("quiet_hours");
+    item.insert(String::from("cron"), AttributeValue::S(String::from("0 9 * * *")));
+    assert_eq!(push_timing(&item, 1_700_000_000), Timing::At(1_700_000_000));
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// The type of a key attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeType {
+    String,
+    Number
+}
+
+impl AttributeType {
+    /// The type as DynamoDB spells it in attribute definitions.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AttributeType::String => "S",
+            AttributeType::Number => "N"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyAttribute {
+    pub name: &'static str,
+    pub attribute_type: AttributeType
+}
+
+/// A partition key, and optionally a sort key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySchema {
+    pub hash: KeyAttribute,
+    pub range: Option<KeyAttribute>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IndexKind {
+    Local,
+    Global
+}
+
+impl Display for IndexKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexKind::Local => write!(f, "local"),
+            IndexKind::Global => write!(f, "global")
+        }
+    }
+}
+
+/// A secondary index. Every index projects all attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Index {
+    pub name: &'static str,
+    pub kind: IndexKind,
+    pub key: KeySchema
+}
+
+/// A table every deployment has. Deployed tables are named by each lambda's
+/// environment; `name` is the base those names are made from, e.g.
+/// `schedule_dev`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Table {
+    pub name: &'static str,
+    pub key: KeySchema,
+    pub indexes: &'static [Index],
+    /// The attribute DynamoDB's TTL expires items by, if it's turned on.
+    pub ttl_attribute: Option<&'static str>
+}
+
+const fn string(name: &'static str) -> KeyAttribute {
+    KeyAttribute { name, attribute_type: AttributeType::String }
+}
+
+const fn number(name: &'static str) -> KeyAttribute {
+    KeyAttribute { name, attribute_type: AttributeType::Number }
+}
+
+const PART_AND_ID: KeySchema = KeySchema { hash: string("part"), range: Some(string("id")) };
+const ID: KeySchema = KeySchema { hash: string("id"), range: None };
+
+/// Schedules by when they're next due, spread over a partition's shards; see
+/// `schedule::Shards`.
+pub const DUE_INDEX: Index = Index {
+    name: "due-index",
+    kind: IndexKind::Global,
+    key: KeySchema { hash: string("shard"), range: Some(number("next_fire")) }
+};
+
+/// Schedules by the entitlement they belong to, then partition. It's global,
+/// so a partition's schedules aren't held to a local index's 10 GB per key,
+/// and reads from it are eventually consistent. Tables made before it have a
+/// local `entitlement-index` on `part` and `entitlement` instead, which
+/// nothing reads any more.
+pub const ENTITLEMENT_INDEX: Index = Index {
+    name: "entitlement-part-index",
+    kind: IndexKind::Global,
+    key: KeySchema { hash: string("entitlement"), range: Some(string("part")) }
+};
+
+/// Entitlements in a partition, by when they end.
+pub const ENDS_INDEX: Index = Index {
+    name: "ends-index",
+    kind: IndexKind::Local,
+    key: KeySchema { hash: string("part"), range: Some(number("ends")) }
+};
+
+pub const SCHEDULES: Table = Table {
+    name: "schedule",
+    key: PART_AND_ID,
+    indexes: &[ENTITLEMENT_INDEX, DUE_INDEX],
+    ttl_attribute: None
+};
+
+/// Schedules of lapsed entitlements, moved out of `SCHEDULES` by
+/// `purge_expired` and kept, as they were, until `add_user` restores them or
+/// they expire.
+pub const ARCHIVED_SCHEDULES: Table = Table {
+    name: "archived_schedule",
+    key: PART_AND_ID,
+    indexes: &[ENTITLEMENT_INDEX],
+    ttl_attribute: Some("expires")
+};
+
+pub const ENTITLEMENTS: Table = Table {
+    name: "entitlements",
+    key: PART_AND_ID,
+    indexes: &[ENDS_INDEX],
+    ttl_attribute: None
+};
+
+pub const PUSH: Table = Table {
+    name: "push",
+    key: ID,
+    indexes: &[],
+    ttl_attribute: None
+};
+
+pub const FIRED: Table = Table {
+    name: "fired",
+    key: ID,
+    indexes: &[],
+    ttl_attribute: Some("expires")
+};
+
+pub const DEAD_LETTERS: Table = Table {
+    name: "dead_letters",
+    key: ID,
+    indexes: &[],
+    ttl_attribute: Some("expires")
+};
+
+pub const RATE_LIMITS: Table = Table {
+    name: "rate_limits",
+    key: ID,
+    indexes: &[],
+    ttl_attribute: Some("expires")
+};
+
+/// Schedules by the tick they're due in, for `Scheduler::Queue`: keyed by
+/// `schedule::queue_key`, then schedule row ID.
+pub const DUE_QUEUE: Table = Table {
+    name: "due_queue",
+    key: KeySchema { hash: string("bucket"), range: Some(string("id")) },
+    indexes: &[],
+    ttl_attribute: Some("expires")
+};
+
+/// The data migrations `selektor-admin migrate` has applied, by version.
+pub const MIGRATIONS: Table = Table {
+    name: "migrations",
+    key: ID,
+    indexes: &[],
+    ttl_attribute: None
+};
+
+pub const TABLES: [Table; 9] = [SCHEDULES, ARCHIVED_SCHEDULES, ENTITLEMENTS, PUSH, FIRED, DEAD_LETTERS, RATE_LIMITS, DUE_QUEUE, MIGRATIONS];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct KeyNames {
+    hash: Option<String>,
+    range: Option<String>
+}
+
+impl KeyNames {
+    fn matches(&self, key: &KeySchema) -> bool {
+        self.hash.as_deref() == Some(key.hash.name) && self.range.as_deref() == key.range.map(|r| r.name)
+    }
+}
+
+impl Display for KeyNames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {})", self.hash.as_deref().unwrap_or("-"), self.range.as_deref().unwrap_or("-"))
+    }
+}
+
+fn key_names(key: &KeySchema) -> KeyNames {
+    KeyNames { hash: Some(key.hash.name.to_string()), range: key.range.map(|r| r.name.to_string()) }
+}
+
+/// A table as `DescribeTable` reports it, built up one key attribute at a
+/// time; see `dynamodb::described`.
+#[derive(Debug, Default, Clone)]
+pub struct DescribedTable {
+    key: KeyNames,
+    indexes: BTreeMap<String, (IndexKind, KeyNames)>,
+    ttl_attribute: Option<String>
+}
+
+impl DescribedTable {
+    pub fn has_index(&self, name: &str) -> bool {
+        self.indexes.contains_key(name)
+    }
+
+    /// Adds a key attribute, to the table's own key if `index` is `None`.
+    /// `key_type` is `HASH` or `RANGE`.
+    pub fn add_key(&mut self, index: Option<(IndexKind, &str)>, attribute: &str, key_type: &str) {
+        let key = match index {
+            Some((kind, name)) => &mut self.indexes.entry(name.to_string()).or_insert_with(|| (kind, KeyNames::default())).1,
+            None => &mut self.key
+        };
+        match key_type {
+            "HASH" => key.hash = Some(attribute.to_string()),
+            _ => key.range = Some(attribute.to_string())
+        }
+    }
+
+    /// Records the attribute TTL is enabled on, if it is.
+    pub fn set_ttl_attribute(&mut self, attribute: Option<&str>) {
+        self.ttl_attribute = attribute.map(str::to_string);
+    }
+}
+
+impl Table {
+    /// Checks a deployed table against this one: its key, that every index
+    /// is there with the same kind and key, and that TTL is on if it should
+    /// be. Indexes that aren't in the schema are left alone. Lists everything
+    /// that's wrong.
+    pub fn verify(&self, deployed: &DescribedTable) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if !deployed.key.matches(&self.key) {
+            problems.push(format!("key is {}, expected {}", deployed.key, key_names(&self.key)));
+        }
+        for index in self.indexes {
+            match deployed.indexes.get(index.name) {
+                None => problems.push(format!("{} index {} is missing", index.kind, index.name)),
+                Some((kind, key)) if *kind != index.kind || !key.matches(&index.key) => problems.push(format!(
+                    "index {} is a {} index on {}, expected a {} index on {}",
+                    index.name, kind, key, index.kind, key_names(&index.key)
+                )),
+                Some(_) => ()
+            }
+        }
+        if let Some(attribute) = self.ttl_attribute {
+            match deployed.ttl_attribute.as_deref() {
+                None => problems.push(format!("TTL isn't enabled, expected it on {}", attribute)),
+                Some(deployed) if deployed != attribute => problems.push(format!("TTL is on {}, expected {}", deployed, attribute)),
+                Some(_) => ()
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+}
+
+#[test]
+fn test_verify() {
+    let mut deployed = DescribedTable::default();
+    deployed.add_key(None, "part", "HASH");
+    deployed.add_key(None, "id", "RANGE");
+    deployed.add_key(Some((IndexKind::Local, "next_fire-index")), "part", "HASH");
+    deployed.add_key(Some((IndexKind::Local, "next_fire-index")), "next_fire", "RANGE");
+    deployed.add_key(Some((IndexKind::Local, "part-entitlement-index")), "part", "HASH");
+    deployed.add_key(Some((IndexKind::Local, "part-entitlement-index")), "entitlement", "RANGE");
+    assert_eq!(
+        SCHEDULES.verify(&deployed),
+        Err("global index entitlement-part-index is missing; global index due-index is missing".to_string())
+    );
+
+    deployed.add_key(Some((IndexKind::Local, "entitlement-part-index")), "part", "HASH");
+    deployed.add_key(Some((IndexKind::Local, "entitlement-part-index")), "entitlement", "RANGE");
+    deployed.add_key(Some((IndexKind::Global, "due-index")), "shard", "HASH");
+    deployed.add_key(Some((IndexKind::Global, "due-index")), "next_fire", "RANGE");
+    assert_eq!(
+        SCHEDULES.verify(&deployed),
+        Err("index entitlement-part-index is a local index on (part, entitlement), expected a global index on (entitlement, part)".to_string())
+    );
+
+    // A table from before the entitlement index was global keeps its local
+    // one alongside.
+    let mut deployed = DescribedTable::default();
+    deployed.add_key(None, "part", "HASH");
+    deployed.add_key(None, "id", "RANGE");
+    deployed.add_key(Some((IndexKind::Local, "entitlement-index")), "part", "HASH");
+    deployed.add_key(Some((IndexKind::Local, "entitlement-index")), "entitlement", "RANGE");
+    deployed.add_key(Some((IndexKind::Global, "entitlement-part-index")), "entitlement", "HASH");
+    deployed.add_key(Some((IndexKind::Global, "entitlement-part-index")), "part", "RANGE");
+    deployed.add_key(Some((IndexKind::Global, "due-index")), "shard", "HASH");
+    deployed.add_key(Some((IndexKind::Global, "due-index")), "next_fire", "RANGE");
+    assert_eq!(SCHEDULES.verify(&deployed), Ok(()));
+
+    let mut deployed = DescribedTable::default();
+    deployed.add_key(None, "id", "HASH");
+    assert_eq!(PUSH.verify(&deployed), Ok(()));
+    assert_eq!(
+        FIRED.verify(&DescribedTable::default()),
+        Err("key is (-, -), expected (id, -); TTL isn't enabled, expected it on expires".to_string())
+    );
+    deployed.set_ttl_attribute(Some("ttl"));
+    assert_eq!(FIRED.verify(&deployed), Err("TTL is on ttl, expected expires".to_string()));
+    deployed.set_ttl_attribute(Some("expires"));
+    assert_eq!(FIRED.verify(&deployed), Ok(()));
+}
@@ -1,70 +1,425 @@
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_dynamodb as ddb;
-use aws_sdk_dynamodb::model::AttributeValue;
+use aws_sdk_dynamodb::error::TransactWriteItemsErrorKind;
+use aws_sdk_dynamodb::model::{AttributeValue, CancellationReason, Delete, Put, TransactWriteItem, Update};
 use lambda_http::Error;
 use std::env;
 use std::str::FromStr;
 use lambda_http::aws_lambda_events::serde::{Deserialize, Serialize};
-use tracing::info;
+use selektor_common::local_time::{parse_time_zone, QuietHours};
+use selektor_common::push_scheduler::{self, schedule_name, PushSchedule, PushScheduler, Timing};
+use selektor_common::payload::PayloadTemplate;
+use std::time::{Duration, SystemTime};
+use selektor_common::schedule::{is_legacy_bucket, queue_key, row_key, version_key, CatchUpPolicy, CronSpec, Jitter, Recurrence, Scheduler, Shards, Tick, LEGACY_BUCKET_SECONDS, QUEUE_RETENTION_SECONDS, stable_hash};
+use chrono_tz::Tz;
+use tracing::{info, warn};
 use tokio_stream::StreamExt;
+use selektor_common::dynamodb::check_tables;
+use selektor_common::schema;
+use selektor_common::schedule_item;
 
 const PARTITION_ID: &str = "PARTITION_ID";
 const TABLE_NAME: &str = "TABLE_NAME";
 const DYNAMODB_ENDPOINT: &str = "DYNAMODB_ENDPOINT";
+const MAX_ID_LENGTH: usize = 128;
+/// DynamoDB's limit on the items in one `TransactWriteItems`.
+const MAX_TRANSACTION_ITEMS: usize = 100;
+/// How many times `get_schedule` reads the schedules before giving up on
+/// getting a consistent version.
+const MAX_READ_ATTEMPTS: usize = 3;
+/// Attempts at a part of an update that runs into other transactions.
+const MAX_TRANSACTION_ATTEMPTS: u32 = 3;
+const TRANSACTION_BACKOFF: Duration = Duration::from_millis(50);
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+/// A schedule as the app sends it. Times are epoch seconds; requests from
+/// older apps still counting in 5 minute buckets are converted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ScheduleEntry {
+    /// The app's own name for this schedule, which stays the same across
+    /// updates. Entries without one are named after what they contain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
     last_fire: u64,
-    fire_interval: u64
+    /// Seconds between fires.
+    #[serde(default)]
+    fire_interval: u64,
+    /// Fires on a cron schedule instead of every `fire_interval`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cron: Option<String>,
+    /// IANA time zone the cron expression and quiet hours are in; UTC if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time_zone: Option<String>,
+    /// Local times during which pushes are held until the window ends.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    quiet_hours: Vec<QuietHours>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<PayloadTemplate>,
+    #[serde(default)]
+    catch_up: CatchUpPolicy
 }
 
-impl PartialEq<ScheduleEntry> for ScheduleEntry {
-    fn eq(&self, other: &Self) -> bool {
-        return self.last_fire == other.last_fire && self.fire_interval == other.fire_interval
+impl ScheduleEntry {
+    fn payload_json(&self) -> Option<String> {
+        self.payload.as_ref().and_then(|p| serde_json::to_string(p).ok())
+    }
+
+    /// This entry with its times in seconds, if they were sent as buckets.
+    /// Without a `time_unit`, a `last_fire` too small to be in epoch seconds
+    /// means buckets, except 0, which apps send for a schedule that's never
+    /// fired, in either unit.
+    fn normalized(&self, time_unit: Option<TimeUnit>) -> ScheduleEntry {
+        let buckets = match time_unit {
+            Some(time_unit) => time_unit == TimeUnit::Buckets,
+            None => self.last_fire > 0 && is_legacy_bucket(self.last_fire)
+        };
+        if buckets {
+            ScheduleEntry {
+                last_fire: self.last_fire * LEGACY_BUCKET_SECONDS,
+                fire_interval: self.fire_interval.saturating_mul(LEGACY_BUCKET_SECONDS),
+                ..self.clone()
+            }
+        } else {
+            self.clone()
+        }
+    }
+
+    /// When this should first fire, moved later by the schedule's jitter
+    /// `offset`, or `None` if a cron schedule has no occurrences after
+    /// `last_fire`.
+    fn next_fire(&self, tick: Tick, offset: u64) -> Option<u64> {
+        let recurrence = match &self.cron {
+            Some(expression) => {
+                let time_zone = self.time_zone.as_ref().and_then(|name| parse_time_zone(name).ok()).unwrap_or(Tz::UTC);
+                Recurrence::Cron(CronSpec::parse(expression).ok()?.in_time_zone(time_zone).with_offset(offset))
+            },
+            None => Recurrence::Interval(self.fire_interval)
+        };
+        recurrence.next_after(self.last_fire + offset, tick)
+    }
+
+    /// Whether `other` fires at the same times. `last_fire` is left out:
+    /// once stored, run_notify moves a schedule along from where it was
+    /// uploaded, and the app's copy falls behind.
+    fn same_timing(&self, other: &ScheduleEntry) -> bool {
+        self.fire_interval == other.fire_interval && self.cron == other.cron && self.time_zone == other.time_zone
+    }
+
+    /// Whether `other` is the same schedule, apart from when it last fired.
+    fn same_schedule(&self, other: &ScheduleEntry) -> bool {
+        self.same_timing(other) && self.quiet_hours == other.quiet_hours
+            && self.payload == other.payload && self.catch_up == other.catch_up
+    }
+
+    /// How the entry's EventBridge schedule fires, when it's next due at
+    /// `next_fire`.
+    fn push_timing(&self, next_fire: u64) -> Timing {
+        Timing::for_schedule(next_fire, Some(self.fire_interval), self.cron.is_some(), &self.quiet_hours)
+    }
+
+    /// A name for an entry the app didn't give one, from what it contains, so
+    /// that sending the same entry again finds the same schedule.
+    fn derived_id(&self) -> String {
+        let definition = serde_json::to_string(&ScheduleEntry { id: None, last_fire: 0, ..self.clone() }).unwrap_or_default();
+        format!("{:016x}", stable_hash(definition.as_bytes()))
     }
 }
 
-impl Eq for ScheduleEntry {}
+/// What a request's times are counted in.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeUnit {
+    Seconds,
+    /// 5 minute buckets since the epoch, as older apps send.
+    Buckets
+}
 
-impl PartialOrd<ScheduleEntry> for ScheduleEntry {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        return Some(
-            if self.last_fire < other.last_fire {
-                Ordering::Less
-            } else if self.last_fire > other.last_fire {
-                Ordering::Greater
-            } else {
-                if self.fire_interval < other.fire_interval {
-                    Ordering::Less
-                } else if self.fire_interval > other.fire_interval {
-                    Ordering::Greater
-                } else {
-                    Ordering::Equal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateScheduleRequest {
+    /// Told apart by `last_fire` if unset; see `ScheduleEntry::normalized`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time_unit: Option<TimeUnit>,
+    entries: Vec<ScheduleEntry>
+}
+
+/// What's wrong with one field of an uploaded schedule. `entry` is the
+/// entry's position in the request, or `None` for the request as a whole.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<usize>,
+    pub field: String,
+    pub message: String
+}
+
+impl FieldError {
+    fn new(entry: Option<usize>, field: &str, message: String) -> FieldError {
+        FieldError { entry, field: field.to_string(), message }
+    }
+}
+
+/// An uploaded schedule that can't be accepted, with everything found wrong
+/// with it.
+#[derive(Serialize, Debug)]
+pub struct ScheduleError {
+    pub reason: String,
+    pub errors: Vec<FieldError>
+}
+
+impl std::error::Error for ScheduleError {}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Why a request body couldn't be read as an `UpdateScheduleRequest`.
+#[derive(Debug)]
+pub enum RequestError {
+    /// It isn't JSON.
+    Malformed(String),
+    /// It's JSON, but a field has the wrong type or an unknown value.
+    Invalid(ScheduleError)
+}
+
+/// Where `path` points in a request, as an entry position and a field.
+fn field_at(path: &serde_path_to_error::Path) -> (Option<usize>, String) {
+    use serde_path_to_error::Segment;
+    let segments: Vec<&Segment> = path.iter().collect();
+    let (entry, rest) = match segments.as_slice() {
+        [Segment::Map { key }, Segment::Seq { index }, rest @ ..] if key == "entries" => (Some(*index), rest),
+        _ => (None, segments.as_slice())
+    };
+    let mut field = String::new();
+    for segment in rest {
+        match segment {
+            Segment::Seq { index } => field.push_str(&format!("[{}]", index)),
+            segment if field.is_empty() => field.push_str(&segment.to_string()),
+            segment => field.push_str(&format!(".{}", segment))
+        }
+    }
+    (entry, if field.is_empty() { "entries".to_string() } else { field })
+}
+
+const MIN_FIRE_INTERVAL_SECONDS: &str = "MIN_FIRE_INTERVAL_SECONDS";
+const MAX_FIRE_INTERVAL_SECONDS: &str = "MAX_FIRE_INTERVAL_SECONDS";
+const MAX_SCHEDULE_ENTRIES: &str = "MAX_SCHEDULE_ENTRIES";
+const MAX_LAST_FIRE_AHEAD_SECONDS: &str = "MAX_LAST_FIRE_AHEAD_SECONDS";
+
+/// Bounds on what a principal can upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Shortest `fire_interval`, in seconds. Never less than a tick.
+    pub min_interval: u64,
+    /// Longest `fire_interval`, in seconds.
+    pub max_interval: u64,
+    /// Most schedules one principal can have.
+    pub max_entries: usize,
+    /// How far past now a `last_fire` may be, in seconds.
+    pub max_ahead: u64
+}
+
+impl Limits {
+    pub fn from_env() -> Result<Limits, String> {
+        fn parse<T: FromStr>(var: &str, default: T) -> Result<T, String> {
+            match env::var(var) {
+                Ok(value) => T::from_str(&value).map_err(|_| format!("invalid {}: {}", var, value)),
+                Err(_) => Ok(default)
+            }
+        }
+        let tick = Tick::from_env()?;
+        Ok(Limits {
+            min_interval: parse(MIN_FIRE_INTERVAL_SECONDS, tick.seconds())?.max(tick.seconds()),
+            max_interval: parse(MAX_FIRE_INTERVAL_SECONDS, 366 * 24 * 60 * 60)?,
+            max_entries: parse(MAX_SCHEDULE_ENTRIES, 64)?,
+            max_ahead: parse(MAX_LAST_FIRE_AHEAD_SECONDS, 24 * 60 * 60)?
+        })
+    }
+}
+
+impl UpdateScheduleRequest {
+    /// Reads a request body, saying where it's wrong if it can't.
+    pub fn parse(body: &[u8]) -> Result<UpdateScheduleRequest, RequestError> {
+        let deserializer = &mut serde_json::Deserializer::from_slice(body);
+        serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let (entry, field) = field_at(e.path());
+            let e = e.into_inner();
+            if !e.is_data() {
+                return RequestError::Malformed(e.to_string())
+            }
+            // The position in the body is no use next to the field.
+            let message = e.to_string();
+            let message = match message.rsplit_once(" at line ") {
+                Some((message, _)) => message.to_string(),
+                None => message
+            };
+            RequestError::Invalid(ScheduleError {
+                reason: "the schedule couldn't be read".to_string(),
+                errors: vec![FieldError { entry, field, message }]
+            })
+        })
+    }
+
+    /// The entries, in epoch seconds, keyed by their IDs. Entries without an
+    /// ID are given a derived one, numbered if the same entry is sent twice.
+    fn entries_by_id(&self) -> HashMap<String, ScheduleEntry> {
+        let mut entries = HashMap::new();
+        for entry in self.entries.iter().map(|entry| entry.normalized(self.time_unit)) {
+            let id = match &entry.id {
+                Some(id) => id.to_owned(),
+                None => {
+                    let derived = entry.derived_id();
+                    (0..).map(|n| if n == 0 { derived.to_owned() } else { format!("{}-{}", derived, n) })
+                        .find(|id| !entries.contains_key(id))
+                        .unwrap_or(derived)
                 }
+            };
+            entries.insert(id, entry);
+        }
+        entries
+    }
+
+    /// Checks the request against `limits`, and that entry IDs are unique,
+    /// that every entry has either a `fire_interval` or a valid cron
+    /// expression, that its time zone and quiet hours make sense, and that its
+    /// payload template fits the push platforms' limits.
+    pub fn validate(&self, limits: &Limits) -> Result<(), ScheduleError> {
+        let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(n) => n.as_secs(),
+            Err(_) => 0
+        };
+        self.validate_at(limits, now)
+    }
+
+    fn validate_at(&self, limits: &Limits, now: u64) -> Result<(), ScheduleError> {
+        let mut errors = Vec::new();
+        if self.entries.len() > limits.max_entries {
+            errors.push(FieldError::new(None, "entries", format!("at most {} schedules are allowed", limits.max_entries)));
+        }
+        let mut ids = HashSet::new();
+        for (i, entry) in self.entries.iter().map(|entry| entry.normalized(self.time_unit)).enumerate() {
+            let mut error = |field: &str, message: String| errors.push(FieldError::new(Some(i), field, message));
+            if let Some(id) = &entry.id {
+                if id.is_empty() || id.len() > MAX_ID_LENGTH {
+                    error("id", format!("must be 1 to {} characters long", MAX_ID_LENGTH));
+                } else if !ids.insert(id.to_owned()) {
+                    error("id", format!("'{}' is used by more than one entry", id));
+                }
+            }
+            if entry.last_fire > now.saturating_add(limits.max_ahead) {
+                error("last_fire", format!("can't be more than {} seconds from now", limits.max_ahead));
             }
-        )
+            match (&entry.cron, entry.fire_interval) {
+                (Some(_), interval) if interval > 0 => error("cron", "can't be given with a fire_interval".to_string()),
+                (Some(expression), _) => if let Err(e) = CronSpec::parse(expression) { error("cron", e) },
+                (None, 0) => error("fire_interval", "is needed without a cron expression".to_string()),
+                (None, interval) if interval < limits.min_interval =>
+                    error("fire_interval", format!("must be at least {} seconds", limits.min_interval)),
+                (None, interval) if interval > limits.max_interval =>
+                    error("fire_interval", format!("must be at most {} seconds", limits.max_interval)),
+                (None, _) => ()
+            }
+            if let Some(time_zone) = &entry.time_zone {
+                if let Err(e) = parse_time_zone(time_zone) { error("time_zone", e) }
+            }
+            for window in &entry.quiet_hours {
+                if let Err(e) = window.validate() { error("quiet_hours", e) }
+            }
+            if let Some(payload) = &entry.payload {
+                if let Err(e) = payload.validate() { error("payload", e.reason) }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ScheduleError { reason: format!("the schedule has {} problem(s)", errors.len()), errors })
+        }
     }
 }
 
-impl Ord for ScheduleEntry {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+fn decode_payload(item: &HashMap<String, AttributeValue>) -> Option<PayloadTemplate> {
+    match item.get("payload") {
+        Some(AttributeValue::S(json)) => match serde_json::from_str(json) {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                warn!("ignoring invalid payload {}: {}", json, e);
+                None
+            }
+        },
+        _ => None
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct UpdateScheduleRequest {
-    entries: Vec<ScheduleEntry>
+fn decode_catch_up(item: &HashMap<String, AttributeValue>) -> CatchUpPolicy {
+    match item.get("catch_up") {
+        Some(AttributeValue::S(policy)) => CatchUpPolicy::from_str(policy).unwrap_or_else(|e| {
+            warn!("{}", e);
+            CatchUpPolicy::default()
+        }),
+        _ => CatchUpPolicy::default()
+    }
 }
 
-fn decode_schedule(item: &HashMap<String, AttributeValue>) -> Option<ScheduleEntry> {
-    if let Some(AttributeValue::N(next_fire_n)) = item.get("next_fire") {
+fn decode_time_zone(item: &HashMap<String, AttributeValue>) -> Option<String> {
+    match item.get("time_zone") {
+        Some(AttributeValue::S(name)) => Some(name.to_string()),
+        _ => None
+    }
+}
+
+fn decode_quiet_hours(item: &HashMap<String, AttributeValue>) -> Vec<QuietHours> {
+    schedule_item::quiet_hours(item).unwrap_or_else(|e| {
+        warn!("ignoring {}", e);
+        Vec::new()
+    })
+}
+
+fn decode_schedule_id(item: &HashMap<String, AttributeValue>) -> Option<String> {
+    match item.get("schedule_id") {
+        Some(AttributeValue::S(id)) => Some(id.to_string()),
+        _ => None
+    }
+}
+
+fn decode_schedule(item: &HashMap<String, AttributeValue>, jitter: Jitter) -> Option<ScheduleEntry> {
+    // Cron schedules keep the last_fire they were uploaded with, since it
+    // can't be worked back out from next_fire.
+    if let (Some(AttributeValue::S(cron)), Some(AttributeValue::N(last_fire_n))) = (item.get("cron"), item.get("last_fire")) {
+        return u64::from_str(last_fire_n).ok().map(|last_fire| ScheduleEntry {
+            id: decode_schedule_id(item),
+            last_fire,
+            fire_interval: 0,
+            cron: Some(cron.to_string()),
+            time_zone: decode_time_zone(item),
+            quiet_hours: decode_quiet_hours(item),
+            payload: decode_payload(item),
+            catch_up: decode_catch_up(item)
+        })
+    }
+    let id = match item.get("id") {
+        Some(AttributeValue::S(id)) => id.as_str(),
+        _ => ""
+    };
+    // A push held by quiet hours moves next_fire, but not the occurrence the
+    // schedule carries on from.
+    if let Some(AttributeValue::N(next_fire_n)) = item.get("deferred_from").or_else(|| item.get("next_fire")) {
         if let Some(AttributeValue::N(fire_interval_n)) = item.get("fire_interval") {
             if let Ok(next_fire) = u64::from_str(next_fire_n) {
                 if let Ok(fire_interval) = u64::from_str(fire_interval_n) {
-                    Some(ScheduleEntry { last_fire: next_fire - fire_interval, fire_interval })
+                    Some(ScheduleEntry {
+                        id: decode_schedule_id(item),
+                        // Saturating, since a row written by hand or by a
+                        // bug shouldn't take the endpoint down.
+                        last_fire: next_fire.saturating_sub(fire_interval.saturating_add(jitter.offset(id))),
+                        fire_interval,
+                        cron: None,
+                        time_zone: decode_time_zone(item),
+                        quiet_hours: decode_quiet_hours(item),
+                        payload: decode_payload(item),
+                        catch_up: decode_catch_up(item)
+                    })
                 } else {
                     None
                 }
@@ -79,74 +434,671 @@ fn decode_schedule(item: &HashMap<String, AttributeValue>) -> Option<ScheduleEnt
     }
 }
 
-pub async fn update_schedule(principal: &String, request: &UpdateScheduleRequest) -> Result<(), Error> {
-    let partition_id = env::var(PARTITION_ID)?;
-    let table_name = env::var(TABLE_NAME)?;
+/// Another update to the principal's schedules got in first.
+#[derive(Debug)]
+pub struct ScheduleConflict {
+    pub reason: String,
+    /// How many parts of a split update were applied before it.
+    pub applied: usize
+}
 
-    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
-    let config = aws_config::from_env().region(region_provider).load().await;
-    let ddb_config = match env::var(DYNAMODB_ENDPOINT) {
-        Ok(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
-        _ => ddb::config::Builder::from(&config).build()
-    };
-    let ddb_client = ddb::Client::from_conf(ddb_config);
+impl std::error::Error for ScheduleConflict {}
+
+impl Display for ScheduleConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// The ETag for a principal's schedules at `version`.
+pub fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
 
-    // Fetch the current schedules.
-    let mut results = ddb_client.query()
+/// Whether an `If-Match` header matches the schedules at `version`. Weak tags
+/// never do, since the comparison is strong, and `*` only matches schedules
+/// that have been stored, i.e. a version above 0.
+fn etag_matches(if_match: &str, version: u64) -> bool {
+    let current = etag(version);
+    if_match.split(',').map(str::trim).any(|tag| (tag == "*" && version > 0) || tag == current)
+}
+
+/// Moves the principal's schedule version from `expected` to `expected + 1`,
+/// failing the transaction if it has already moved on. Version 0 is a
+/// principal with no version row yet.
+fn bump_version(table_name: &str, partition_id: &str, principal: &str, expected: u64) -> TransactWriteItem {
+    let update = Update::builder()
         .table_name(table_name.to_owned())
-        .index_name("part-entitlement-index")
-        .key_condition_expression("#part = :part AND #ent = :ent")
-        .expression_attribute_names("#part", "part")
-        .expression_attribute_names("#ent", "entitlement")
-        .expression_attribute_values(":part", AttributeValue::S(partition_id.to_owned()))
-        .expression_attribute_values(":ent", AttributeValue::S(principal.to_string()))
-        .into_paginator()
-        .send();
-    let mut existing_schedules: Vec<ScheduleEntry> = Vec::new();
-    let mut existing_ids: Vec<String> = Vec::new();
-    while let Some(res) = results.next().await {
-        match res?.items() {
-            Some(items) => for item in items {
-                if let Some(sched) = decode_schedule(item) {
-                    if let Some(AttributeValue::S(id)) = item.get("id") {
-                        existing_ids.push(id.to_string());
-                    }
-                    existing_schedules.push(sched)
+        .key("part", AttributeValue::S(partition_id.to_owned()))
+        .key("id", AttributeValue::S(version_key(principal)))
+        .update_expression("SET #version = :next")
+        .expression_attribute_names("#version", "schedule_version")
+        .expression_attribute_values(":next", AttributeValue::N((expected + 1).to_string()));
+    let update = if expected == 0 {
+        update.condition_expression("attribute_not_exists(#version)")
+    } else {
+        update.condition_expression("#version = :expected")
+            .expression_attribute_values(":expected", AttributeValue::N(expected.to_string()))
+    };
+    TransactWriteItem::builder().update(update.build()).build()
+}
+
+/// Queues schedule row `row_id`, in `shard`, to be read by the tick starting
+/// at `bucket`, for when it's due at `next_fire`.
+fn queue_entry(table_name: &str, shard: &str, row_id: &str, next_fire: u64, bucket: u64) -> TransactWriteItem {
+    TransactWriteItem::builder()
+        .put(Put::builder()
+            .table_name(table_name.to_owned())
+            .item("bucket", AttributeValue::S(queue_key(shard, bucket)))
+            .item("id", AttributeValue::S(row_id.to_owned()))
+            .item("next_fire", AttributeValue::N(next_fire.to_string()))
+            .item("expires", AttributeValue::N((bucket + QUEUE_RETENTION_SECONDS).to_string()))
+            .build())
+        .build()
+}
+
+/// A schedule as it's stored, with when it's next due and when a push for
+/// it last got through.
+#[derive(Serialize, Clone, Debug)]
+pub struct StoredSchedule {
+    #[serde(flatten)]
+    entry: ScheduleEntry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_fire: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_delivered: Option<u64>,
+    /// Stored before schedules had IDs of their own, so its `id` is made up
+    /// from what it contains. Sending it back stores it under that ID.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    legacy: bool
+}
+
+impl StoredSchedule {
+    fn decode(item: &HashMap<String, AttributeValue>, jitter: Jitter) -> Option<StoredSchedule> {
+        decode_schedule(item, jitter).map(|entry| StoredSchedule {
+            entry,
+            next_fire: decode_number(item, "next_fire"),
+            last_delivered: decode_number(item, "last_delivered"),
+            legacy: false
+        })
+    }
+
+    /// A schedule from a row without an ID, named after what it contains.
+    fn legacy(mut self) -> StoredSchedule {
+        self.entry.id = Some(self.entry.derived_id());
+        StoredSchedule { legacy: true, ..self }
+    }
+}
+
+/// A principal's schedules, at `version`.
+#[derive(Serialize, Clone, Debug)]
+pub struct ScheduleSet {
+    pub version: u64,
+    entries: Vec<StoredSchedule>
+}
+
+fn decode_number(item: &HashMap<String, AttributeValue>, name: &str) -> Option<u64> {
+    match item.get(name) {
+        Some(AttributeValue::N(n)) => u64::from_str(n).ok(),
+        _ => None
+    }
+}
+
+/// The table's settings and a client for it, from the environment.
+struct ScheduleTable {
+    ddb_client: ddb::Client,
+    table_name: String,
+    partition_id: String,
+    jitter: Jitter,
+    shards: Shards,
+    scheduler: Scheduler
+}
+
+impl ScheduleTable {
+    async fn from_env() -> Result<ScheduleTable, Error> {
+        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+        let config = aws_config::from_env().region(region_provider).load().await;
+        let ddb_config = match env::var(DYNAMODB_ENDPOINT) {
+            Ok(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
+            _ => ddb::config::Builder::from(&config).build()
+        };
+        Ok(ScheduleTable {
+            ddb_client: ddb::Client::from_conf(ddb_config),
+            table_name: env::var(TABLE_NAME)?,
+            partition_id: env::var(PARTITION_ID)?,
+            jitter: Jitter::from_env()?,
+            shards: Shards::from_env()?,
+            scheduler: Scheduler::from_env()?
+        })
+    }
+
+    async fn version(&self, principal: &str) -> Result<u64, Error> {
+        let version_row = self.ddb_client.get_item()
+            .table_name(self.table_name.to_owned())
+            .key("part", AttributeValue::S(self.partition_id.to_owned()))
+            .key("id", AttributeValue::S(version_key(principal)))
+            .consistent_read(true)
+            .send()
+            .await?;
+        Ok(version_row.item().and_then(|item| decode_number(item, "schedule_version")).unwrap_or(0))
+    }
+
+    /// The principal's schedules by ID, and the keys of any rows from before
+    /// schedules had IDs of their own, with what's in them if they decode.
+    /// Rows keyed by `row_key` are read from the table itself, consistently,
+    /// so that they match the version read before them; older rows can only
+    /// be found through the entitlement index, but nothing writes those any
+    /// more.
+    async fn schedules(&self, principal: &str) -> Result<(HashMap<String, StoredSchedule>, Vec<(String, Option<StoredSchedule>)>), Error> {
+        let mut rows = self.ddb_client.query()
+            .table_name(self.table_name.to_owned())
+            .key_condition_expression("#part = :part AND begins_with(#id, :prefix)")
+            .filter_expression("#ent = :ent")
+            .expression_attribute_names("#part", "part")
+            .expression_attribute_names("#id", "id")
+            .expression_attribute_names("#ent", "entitlement")
+            .expression_attribute_values(":part", AttributeValue::S(self.partition_id.to_owned()))
+            .expression_attribute_values(":prefix", AttributeValue::S(row_key(principal, "")))
+            .expression_attribute_values(":ent", AttributeValue::S(principal.to_string()))
+            .consistent_read(true)
+            .into_paginator()
+            .items()
+            .send();
+        let mut schedules = HashMap::new();
+        let mut unnamed = Vec::new();
+        while let Some(item) = rows.next().await {
+            let item = item?;
+            let row_id = match item.get("id") {
+                Some(AttributeValue::S(id)) => id.to_string(),
+                _ => continue
+            };
+            match (decode_schedule_id(&item), StoredSchedule::decode(&item, self.jitter)) {
+                (Some(id), Some(stored)) if row_id == row_key(principal, &id) => {
+                    schedules.insert(id, stored);
+                },
+                (_, stored) => unnamed.push((row_id, stored))
+            }
+        }
+        let mut legacy = self.ddb_client.query()
+            .table_name(self.table_name.to_owned())
+            .index_name(schema::ENTITLEMENT_INDEX.name)
+            .key_condition_expression("#ent = :ent AND #part = :part")
+            .filter_expression("attribute_not_exists(#schedule_id)")
+            .expression_attribute_names("#ent", "entitlement")
+            .expression_attribute_names("#part", "part")
+            .expression_attribute_names("#schedule_id", "schedule_id")
+            .expression_attribute_values(":ent", AttributeValue::S(principal.to_string()))
+            .expression_attribute_values(":part", AttributeValue::S(self.partition_id.to_owned()))
+            .into_paginator()
+            .items()
+            .send();
+        while let Some(item) = legacy.next().await {
+            let item = item?;
+            if let Some(AttributeValue::S(row_id)) = item.get("id") {
+                if !unnamed.iter().any(|(unnamed_id, _)| unnamed_id == row_id) {
+                    unnamed.push((row_id.to_string(), StoredSchedule::decode(&item, self.jitter)));
+                }
+            }
+        }
+        Ok((schedules, unnamed))
+    }
+}
+
+/// Reads back the principal's schedules. The version is read on both sides
+/// of them, and they're read again should it move in between, so that the
+/// version given matches the schedules.
+///
+/// Rows from before schedules had IDs are included, flagged `legacy`, under
+/// the ID `update_schedule` gives an entry without one, unless a schedule
+/// already has that ID.
+pub async fn get_schedule(principal: &str) -> Result<ScheduleSet, Error> {
+    let table = ScheduleTable::from_env().await?;
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let version = table.version(principal).await?;
+        let (mut schedules, unnamed) = table.schedules(principal).await?;
+        if table.version(principal).await? == version {
+            for stored in unnamed.into_iter().filter_map(|(_, stored)| stored) {
+                let stored = stored.legacy();
+                if let Some(id) = stored.entry.id.to_owned() {
+                    schedules.entry(id).or_insert(stored);
                 }
             }
-            None => break
+            let mut entries: Vec<StoredSchedule> = schedules.into_values().collect();
+            entries.sort_by(|a, b| a.entry.id.cmp(&b.entry.id));
+            return Ok(ScheduleSet { version, entries })
         }
     }
-    existing_schedules.sort();
+    Err(Error::from(ScheduleConflict {
+        reason: "the schedules kept changing while being read".to_string(),
+        applied: 0
+    }))
+}
 
-    let mut new_sched = request.entries.to_vec();
-    new_sched.sort();
+/// Brings the principal's stored schedules in line with `request`: entries
+/// whose IDs aren't stored yet are added, stored ones the request leaves out
+/// are removed, and ones that changed are rewritten. A schedule that fires at
+/// different times starts over from the `last_fire` sent; otherwise it keeps
+/// its place, and unchanged schedules aren't written at all.
+///
+/// The changes are written in transactions that also move the principal's
+/// schedule version on, so they're applied whole, and an update racing
+/// another fails with `ScheduleConflict` instead of mixing in with it. Up to
+/// `MAX_TRANSACTION_ITEMS - 1` changes go in one transaction. Beyond that they
+/// are split, and each part bumps the version again. A part that runs into
+/// another transaction, such as a tick claiming one of the schedules, wrote
+/// nothing and is tried again.
+///
+/// Parts that were applied aren't rolled back. Should a later part fail
+/// because another update got in, the other update read the schedules after
+/// the parts before it, and so replaces the whole set with its own; undoing
+/// them would undo some of it. Should one fail otherwise, sending the request
+/// again finishes the job. Returns the version the schedules are now at.
+///
+/// With `if_match`, the `If-Match` header of the request, nothing is changed
+/// unless it names the current version. It's checked once, before anything
+/// is written; a conflict in a later part says how many parts were applied.
+///
+/// With `push_scheduler`, from [`push_scheduler`], each schedule gets a push
+/// schedule that fires it.
+pub async fn update_schedule(principal: &String, request: &UpdateScheduleRequest, if_match: Option<&str>, push_scheduler: Option<&dyn PushScheduler>) -> Result<u64, Error> {
+    let tick = Tick::from_env()?;
+    let table = ScheduleTable::from_env().await?;
+    let ScheduleTable { ddb_client, table_name, partition_id, jitter, shards, scheduler } = &table;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
 
-    if existing_schedules == new_sched {
-        info!("schedules are equal, skipping update");
-        return Ok(())
+    // The version is read before the schedules, so that anything written
+    // after it moves the version on and fails the transaction below.
+    let mut version = table.version(principal).await?;
+    if let Some(if_match) = if_match {
+        if !etag_matches(if_match, version) {
+            return Err(Error::from(ScheduleConflict {
+                reason: format!("the schedules are at {}, not {}", etag(version), if_match),
+                applied: 0
+            }))
+        }
     }
 
-    for id in existing_ids {
-        ddb_client.delete_item()
-            .table_name(table_name.to_owned())
-            .key("part", AttributeValue::S(partition_id.to_owned()))
-            .key("id", AttributeValue::S(id.to_owned()))
-            .send()
-            .await?;
+    // Rows from before schedules had IDs of their own are replaced.
+    let (existing, unnamed) = table.schedules(principal).await?;
+    let mut removed: Vec<String> = unnamed.into_iter().map(|(row_id, _)| row_id).collect();
+    let entries = request.entries_by_id();
+    removed.extend(existing.keys()
+        .filter(|id| !entries.contains_key(*id))
+        .map(|id| row_key(principal, id)));
+    // Grouped by schedule, so a row and its queue entry are written together.
+    let mut changes: Vec<Vec<TransactWriteItem>> = Vec::new();
+    let mut push_schedules: Vec<PushSchedule> = Vec::new();
+    let mut removed_push_schedules: Vec<String> = removed.iter().map(|id| schedule_name(partition_id, id)).collect();
+    let mut added = 0;
+    let mut modified = 0;
+    for (id, sched) in &entries {
+        let row_id = row_key(principal, id);
+        match existing.get(id) {
+            Some(current) if current.entry.same_schedule(sched) => continue,
+            Some(current) if current.entry.same_timing(sched) => {
+                // Leave next_fire to run_notify; only what's sent changes.
+                let mut update = Update::builder()
+                    .table_name(table_name.to_owned())
+                    .key("part", AttributeValue::S(partition_id.to_owned()))
+                    .key("id", AttributeValue::S(row_id.to_owned()))
+                    .expression_attribute_names("#catch_up", "catch_up")
+                    .expression_attribute_values(":catch_up", AttributeValue::S(sched.catch_up.to_string()));
+                let mut sets = vec!["#catch_up = :catch_up"];
+                let mut removes = Vec::new();
+                update = update.expression_attribute_names("#payload", "payload");
+                match sched.payload_json() {
+                    Some(payload) => {
+                        sets.push("#payload = :payload");
+                        update = update.expression_attribute_values(":payload", AttributeValue::S(payload));
+                    },
+                    None => removes.push("#payload")
+                }
+                update = update.expression_attribute_names("#quiet_hours", "quiet_hours");
+                if sched.quiet_hours.is_empty() {
+                    removes.push("#quiet_hours");
+                } else {
+                    sets.push("#quiet_hours = :quiet_hours");
+                    update = update.expression_attribute_values(":quiet_hours", AttributeValue::S(serde_json::to_string(&sched.quiet_hours)?));
+                }
+                let mut expression = format!("SET {}", sets.join(", "));
+                if !removes.is_empty() {
+                    expression = format!("{} REMOVE {}", expression, removes.join(", "));
+                }
+                changes.push(vec![TransactWriteItem::builder().update(update.update_expression(expression).build()).build()]);
+                // Quiet hours decide whether it can fire at a rate.
+                if let Some(next_fire) = current.next_fire {
+                    push_schedules.push(PushSchedule::new(partition_id, &row_id, sched.push_timing(next_fire)));
+                }
+                modified += 1;
+            },
+            current => {
+                let mut put = Put::builder()
+                    .table_name(table_name.to_owned())
+                    .item("part", AttributeValue::S(partition_id.to_owned()))
+                    .item("id", AttributeValue::S(row_id.to_owned()))
+                    .item("schedule_id", AttributeValue::S(id.to_owned()))
+                    .item("shard", AttributeValue::S(shards.key(partition_id, &row_id)))
+                    .item("entitlement", AttributeValue::S(principal.to_owned()))
+                    .item("catch_up", AttributeValue::S(sched.catch_up.to_string()));
+                let next_fire = sched.next_fire(tick, jitter.offset(&row_id));
+                if let Some(next_fire) = next_fire {
+                    put = put.item("next_fire", AttributeValue::N(next_fire.to_string()));
+                }
+                put = match &sched.cron {
+                    Some(cron) => put
+                        .item("cron", AttributeValue::S(cron.to_owned()))
+                        .item("last_fire", AttributeValue::N(sched.last_fire.to_string())),
+                    None => put.item("fire_interval", AttributeValue::N(sched.fire_interval.to_string()))
+                };
+                if let Some(payload) = sched.payload_json() {
+                    put = put.item("payload", AttributeValue::S(payload));
+                }
+                if let Some(time_zone) = &sched.time_zone {
+                    put = put.item("time_zone", AttributeValue::S(time_zone.to_owned()));
+                }
+                if !sched.quiet_hours.is_empty() {
+                    put = put.item("quiet_hours", AttributeValue::S(serde_json::to_string(&sched.quiet_hours)?));
+                }
+                if let Some(last_delivered) = current.and_then(|c| c.last_delivered) {
+                    put = put.item("last_delivered", AttributeValue::N(last_delivered.to_string()));
+                }
+                let mut change = vec![TransactWriteItem::builder().put(put.build()).build()];
+                // Queue entries for the old timing are left to expire; the
+                // tick ignores them, as they don't match `next_fire`.
+                if let (Some(queue_table), Some(next_fire)) = (scheduler.queue_table(), next_fire) {
+                    let shard = shards.key(partition_id, &row_id);
+                    change.push(queue_entry(queue_table, &shard, &row_id, next_fire, tick.queue_bucket(next_fire, now)));
+                }
+                changes.push(change);
+                match next_fire {
+                    Some(next_fire) => push_schedules.push(PushSchedule::new(partition_id, &row_id, sched.push_timing(next_fire))),
+                    None => removed_push_schedules.push(schedule_name(partition_id, &row_id))
+                }
+                if current.is_some() { modified += 1 } else { added += 1 }
+            }
+        }
+    }
+    for id in &removed {
+        changes.push(vec![TransactWriteItem::builder()
+            .delete(Delete::builder()
+                .table_name(table_name.to_owned())
+                .key("part", AttributeValue::S(partition_id.to_owned()))
+                .key("id", AttributeValue::S(id.to_owned()))
+                .build())
+            .build()]);
     }
 
-    for sched in new_sched {
-        ddb_client.put_item()
-            .table_name(table_name.to_owned())
-            .item("part", AttributeValue::S(partition_id.to_owned()))
-            .item("id", AttributeValue::S(uuid::Uuid::new_v4().to_string()))
-            .item("entitlement", AttributeValue::S(principal.to_owned()))
-            .item("next_fire", AttributeValue::N((sched.last_fire + sched.fire_interval).to_string()))
-            .item("fire_interval", AttributeValue::N(sched.fire_interval.to_string()))
-            .send()
-            .await?;
+    if changes.is_empty() {
+        info!("schedules are unchanged at version {}", version);
+        return Ok(version)
+    }
+
+    // Push schedules are made before the rows are written, so that no row is
+    // left without one, and removed after. One that fires for a row that's
+    // gone or moved on is removed or put right by the deliver function.
+    if let Some(push_scheduler) = push_scheduler {
+        for push_schedule in &push_schedules {
+            push_scheduler.put(push_schedule).await?;
+        }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    let transactions = pack(changes, MAX_TRANSACTION_ITEMS - 1);
+    let parts = transactions.len();
+    for (part, transaction) in transactions.into_iter().enumerate() {
+        let mut items = vec![bump_version(table_name, partition_id, principal, version)];
+        items.extend(transaction);
+        let mut attempt = 1;
+        loop {
+            let result = ddb_client.transact_write_items()
+                .set_transact_items(Some(items.clone()))
+                .send()
+                .await;
+            let e = match result {
+                Ok(_) => break,
+                Err(e) => e.into_service_error()
+            };
+            let cancellation = match &e.kind {
+                TransactWriteItemsErrorKind::TransactionCanceledException(canceled) => {
+                    Cancellation::of(canceled.cancellation_reasons().unwrap_or_default())
+                },
+                _ => Cancellation::Other
+            };
+            match cancellation {
+                Cancellation::Contended if attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    warn!("part {} of {} for {} ran into another transaction, retrying", part + 1, parts, principal);
+                    tokio::time::sleep(TRANSACTION_BACKOFF * 2u32.pow(attempt - 1)).await;
+                    attempt += 1;
+                },
+                Cancellation::LostRace => {
+                    warn!("schedules for {} changed underneath version {}, after {} of {} parts", principal, version, part, parts);
+                    let reason = if part == 0 {
+                        "the schedules were changed by another update".to_string()
+                    } else {
+                        format!("the schedules were changed by another update after {} of {} parts of this one were applied", part, parts)
+                    };
+                    return Err(Error::from(ScheduleConflict { reason, applied: part }))
+                },
+                _ => return Err(Error::from(e))
+            }
+        }
+        version += 1;
+    }
+
+    if let Some(push_scheduler) = push_scheduler {
+        for name in &removed_push_schedules {
+            push_scheduler.delete(name).await?;
+        }
+    }
+
+    info!("{} schedules added, {} modified, {} removed, now at version {}", added, modified, removed.len(), version);
+    Ok(version)
+}
+
+/// Why a transaction of an update was canceled, from the reasons given for
+/// each of its items.
+#[derive(Debug, PartialEq, Eq)]
+enum Cancellation {
+    /// A condition failed: the version moved on, so another update got in.
+    LostRace,
+    /// It ran into another transaction on the same items, such as a tick's
+    /// claim, and wrote nothing; it can be tried again.
+    Contended,
+    Other
+}
+
+impl Cancellation {
+    fn of(reasons: &[CancellationReason]) -> Cancellation {
+        let codes: Vec<&str> = reasons.iter().filter_map(|reason| reason.code()).collect();
+        if codes.contains(&"ConditionalCheckFailed") {
+            Cancellation::LostRace
+        } else if codes.contains(&"TransactionConflict") {
+            Cancellation::Contended
+        } else {
+            Cancellation::Other
+        }
+    }
+}
+
+/// Packs groups of items into as few lists of at most `size` items as it
+/// can, in order, without splitting a group.
+fn pack<T>(groups: Vec<Vec<T>>, size: usize) -> Vec<Vec<T>> {
+    let mut packed: Vec<Vec<T>> = Vec::new();
+    for group in groups {
+        match packed.last_mut() {
+            Some(last) if last.len() + group.len() <= size => last.extend(group),
+            _ => packed.push(group)
+        }
+    }
+    packed
+}
+
+/// Fails unless the schedule table, and the due queue if schedules are
+/// queued, match `selektor_common::schema`.
+pub async fn check_schema() -> Result<(), Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    let ddb_config = match env::var(DYNAMODB_ENDPOINT) {
+        Ok(endpoint) => ddb::config::Builder::from(&config).endpoint_url(endpoint).build(),
+        _ => ddb::config::Builder::from(&config).build()
+    };
+    let ddb_client = ddb::Client::from_conf(ddb_config);
+    let mut tables = vec![(env::var(TABLE_NAME)?, schema::SCHEDULES)];
+    if let Some(queue_table) = Scheduler::from_env()?.queue_table() {
+        tables.push((queue_table.to_string(), schema::DUE_QUEUE));
+    }
+    check_tables(&ddb_client, &tables).await
+}
+
+/// The push scheduler for the deployment's `SCHEDULER`, if it uses one.
+pub async fn push_scheduler() -> Result<Option<Box<dyn PushScheduler>>, Error> {
+    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let config = aws_config::from_env().region(region_provider).load().await;
+    Ok(push_scheduler::for_scheduler(&Scheduler::from_env()?, &config)?)
+}
+
+#[test]
+fn test_validate() {
+    let limits = Limits { min_interval: 300, max_interval: 86_400, max_entries: 3, max_ahead: 3_600 };
+    let now = 1_700_000_000;
+    let request: UpdateScheduleRequest = serde_json::from_str(r#"{"entries": [
+        {"id": "a", "last_fire": 1700000000, "fire_interval": 3600},
+        {"id": "b", "last_fire": 1700000000, "cron": "0 9 * * Mon-Fri", "time_zone": "Europe/Berlin"}
+    ]}"#).unwrap();
+    assert!(request.validate_at(&limits, now).is_ok());
+
+    let request: UpdateScheduleRequest = serde_json::from_str(r#"{"entries": [
+        {"id": "a", "last_fire": 1700000000, "fire_interval": 0},
+        {"id": "a", "last_fire": 1700000000, "fire_interval": 100000},
+        {"last_fire": 1800000000, "fire_interval": 60, "time_zone": "Mars/Olympus"},
+        {"last_fire": 1700000000, "fire_interval": 3600}
+    ]}"#).unwrap();
+    let errors = request.validate_at(&limits, now).unwrap_err().errors;
+    let fields: Vec<(Option<usize>, &str)> = errors.iter().map(|e| (e.entry, e.field.as_str())).collect();
+    assert_eq!(fields, vec![
+        (None, "entries"),
+        (Some(0), "fire_interval"),
+        (Some(1), "id"),
+        (Some(1), "fire_interval"),
+        (Some(2), "last_fire"),
+        (Some(2), "fire_interval"),
+        (Some(2), "time_zone")
+    ]);
+}
+
+#[test]
+fn test_parse() {
+    assert!(UpdateScheduleRequest::parse(br#"{"entries": [{"last_fire": 1700000000, "fire_interval": 3600}]}"#).is_ok());
+    let invalid = |body: &str| match UpdateScheduleRequest::parse(body.as_bytes()) {
+        Err(RequestError::Invalid(e)) => e.errors,
+        other => panic!("expected a field error, got {:?}", other)
+    };
+    let errors = invalid(r#"{"entries": [
+        {"last_fire": 1700000000, "fire_interval": 3600},
+        {"last_fire": 1700000000, "fire_interval": -1}
+    ]}"#);
+    assert_eq!(errors.len(), 1);
+    assert_eq!((errors[0].entry, errors[0].field.as_str()), (Some(1), "fire_interval"));
+    assert!(errors[0].message.starts_with("invalid value: integer `-1`"), "{}", errors[0].message);
+    let errors = invalid(r#"{"entries": [{"last_fire": "yesterday"}]}"#);
+    assert_eq!((errors[0].entry, errors[0].field.as_str()), (Some(0), "last_fire"));
+    let errors = invalid(r#"{"entries": [{"last_fire": 1700000000, "catch_up": "sometimes"}]}"#);
+    assert_eq!((errors[0].entry, errors[0].field.as_str()), (Some(0), "catch_up"));
+    let errors = invalid(r#"{"entries": [{"last_fire": 1700000000, "quiet_hours": [{"start": 7}]}]}"#);
+    assert_eq!((errors[0].entry, errors[0].field.as_str()), (Some(0), "quiet_hours[0].start"));
+    let errors = invalid(r#"{"time_unit": "days", "entries": []}"#);
+    assert_eq!((errors[0].entry, errors[0].field.as_str()), (None, "time_unit"));
+    let errors = invalid(r#"{}"#);
+    assert_eq!((errors[0].entry, errors[0].field.as_str()), (None, "entries"));
+    assert!(matches!(UpdateScheduleRequest::parse(b"{\"entries\": ["), Err(RequestError::Malformed(_))));
+}
+
+#[test]
+fn test_normalized() {
+    let entry = |last_fire: u64| ScheduleEntry {
+        id: None, last_fire, fire_interval: 12, cron: None, time_zone: None,
+        quiet_hours: Vec::new(), payload: None, catch_up: CatchUpPolicy::default()
+    };
+    let normalized = entry(5_612_345).normalized(None);
+    assert_eq!((normalized.last_fire, normalized.fire_interval), (5_612_345 * 300, 3600));
+    // Never fired, which could be either unit; taken as seconds unless said.
+    let normalized = entry(0).normalized(None);
+    assert_eq!((normalized.last_fire, normalized.fire_interval), (0, 12));
+    let normalized = entry(0).normalized(Some(TimeUnit::Buckets));
+    assert_eq!((normalized.last_fire, normalized.fire_interval), (0, 3600));
+    let normalized = entry(5_612_345).normalized(Some(TimeUnit::Seconds));
+    assert_eq!((normalized.last_fire, normalized.fire_interval), (5_612_345, 12));
+}
+
+#[test]
+fn test_etag_matches() {
+    assert!(etag_matches("\"7\"", 7));
+    assert!(etag_matches("\"6\", \"7\"", 7));
+    assert!(!etag_matches("W/\"7\"", 7));
+    assert!(etag_matches("*", 7));
+    // Nothing stored yet.
+    assert!(!etag_matches("*", 0));
+    assert!(etag_matches("\"0\"", 0));
+}
+
+#[test]
+fn test_cancellation() {
+    let reasons = |codes: &[&str]| codes.iter()
+        .map(|code| CancellationReason::builder().code(*code).build())
+        .collect::<Vec<CancellationReason>>();
+    assert_eq!(Cancellation::of(&reasons(&["ConditionalCheckFailed", "None"])), Cancellation::LostRace);
+    assert_eq!(Cancellation::of(&reasons(&["None", "None", "ConditionalCheckFailed"])), Cancellation::LostRace);
+    assert_eq!(Cancellation::of(&reasons(&["None", "TransactionConflict", "None"])), Cancellation::Contended);
+    assert_eq!(Cancellation::of(&reasons(&["ThrottlingError", "None"])), Cancellation::Other);
+    assert_eq!(Cancellation::of(&[]), Cancellation::Other);
+}
+
+#[test]
+fn test_pack() {
+    assert_eq!(pack(vec![vec![1, 2], vec![3], vec![4, 5]], 3), vec![vec![1, 2, 3], vec![4, 5]]);
+    assert_eq!(pack(vec![vec![1], vec![2, 3], vec![4, 5]], 2), vec![vec![1], vec![2, 3], vec![4, 5]]);
+    assert!(pack(Vec::<Vec<u8>>::new(), 2).is_empty());
+}
+
+#[test]
+fn test_decode_schedule_underflow() {
+    let item = HashMap::from([
+        ("id".to_string(), AttributeValue::S("p#a".to_string())),
+        ("schedule_id".to_string(), AttributeValue::S("a".to_string())),
+        ("next_fire".to_string(), AttributeValue::N("100".to_string())),
+        ("fire_interval".to_string(), AttributeValue::N("3600".to_string()))
+    ]);
+    assert_eq!(decode_schedule(&item, Jitter::new(600)).map(|s| s.last_fire), Some(0));
+}
+
+#[test]
+fn test_legacy_schedule() {
+    let item = HashMap::from([
+        ("id".to_string(), AttributeValue::S("0b4f7c4e-legacy".to_string())),
+        ("next_fire".to_string(), AttributeValue::N("1700003600".to_string())),
+        ("fire_interval".to_string(), AttributeValue::N("3600".to_string()))
+    ]);
+    let stored = StoredSchedule::decode(&item, Jitter::new(0)).unwrap();
+    let json = serde_json::to_value(&stored).unwrap();
+    assert_eq!(json.get("id"), None);
+    assert_eq!(json.get("legacy"), None);
+    // Named the way an upload of the same entry without an ID would be.
+    let request: UpdateScheduleRequest = serde_json::from_str(r#"{"entries": [
+        {"last_fire": 1700000000, "fire_interval": 3600}
+    ]}"#).unwrap();
+    let json = serde_json::to_value(stored.legacy()).unwrap();
+    assert_eq!(json["legacy"], true);
+    assert!(request.entries_by_id().contains_key(json["id"].as_str().unwrap()));
+}
+
+#[test]
+fn test_push_timing() {
+    let request: UpdateScheduleRequest = serde_json::from_str(r#"{"entries": [
+        {"id": "a", "last_fire": 1700000000, "fire_interval": 3600},
+        {"id": "b", "last_fire": 1700000000, "fire_interval": 3600, "quiet_hours": [{"start": "22:00", "end": "07:00"}]},
+        {"id": "c", "last_fire": 1700000000, "cron": "0 9 * * Mon-Fri"}
+    ]}"#).unwrap();
+    let entries = request.entries_by_id();
+    assert_eq!(entries["a"].push_timing(1_700_003_600), Timing::Rate { start: 1_700_003_600, interval: 3600 });
+    assert_eq!(entries["b"].push_timing(1_700_003_600), Timing::At(1_700_003_600));
+    assert_eq!(entries["c"].push_timing(1_700_035_200), Timing::At(1_700_035_200));
+}
@@ -1,23 +1,54 @@
 use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response};
+use lambda_http::http::Method;
 use lambda_http::aws_lambda_events::serde_json;
 use lambda_http::aws_lambda_events::serde_json::Value;
 use lambda_http::request::RequestContext;
+use selektor_common::push_scheduler::PushScheduler;
 use tracing::{debug, info};
-use update_sched::{UpdateScheduleRequest, update_schedule};
+use update_sched::{etag, get_schedule, Limits, RequestError, ScheduleConflict, UpdateScheduleRequest, update_schedule};
 
 /// This is the main body for the function.
 /// Write your code inside it.
 /// There are some code example in the following URLs:
 /// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
-async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
+async fn function_handler(event: Request, push_scheduler: Option<&dyn PushScheduler>) -> Result<Response<Body>, Error> {
     debug!("request: {:?}, context: {:?}", event, event.request_context());
     let resp = match event.request_context() {
         RequestContext::ApiGatewayV1(ctx) => {
             match ctx.authorizer.get("principalId") {
+                Some(Value::String(principal)) if event.method() == Method::GET => {
+                    let schedules = match get_schedule(principal).await {
+                        Ok(schedules) => schedules,
+                        Err(e) => match e.downcast_ref::<ScheduleConflict>() {
+                            Some(conflict) => return Ok(
+                                Response::builder()
+                                    .status(409)
+                                    .header("content-type", "text/plain")
+                                    .body(conflict.to_string().into())
+                                    .map_err(Box::new)?
+                            ),
+                            None => return Err(e)
+                        }
+                    };
+                    Response::builder()
+                        .status(200)
+                        .header("content-type", "application/json")
+                        .header("etag", etag(schedules.version))
+                        .body(serde_json::to_string(&schedules)?.into())
+                        .map_err(Box::new)?
+                },
+                Some(Value::String(_)) if event.method() != Method::PUT && event.method() != Method::POST => {
+                    Response::builder()
+                        .status(405)
+                        .header("allow", "GET, PUT, POST")
+                        .header("content-type", "text/plain")
+                        .body("Method Not Allowed".into())
+                        .map_err(Box::new)?
+                },
                 Some(Value::String(principal)) => {
-                    let request: serde_json::Result<UpdateScheduleRequest> = match event.body() {
-                        Body::Text(s) => serde_json::from_str(s),
-                        Body::Binary(b) => serde_json::from_slice(b),
+                    let request = match event.body() {
+                        Body::Text(s) => UpdateScheduleRequest::parse(s.as_bytes()),
+                        Body::Binary(b) => UpdateScheduleRequest::parse(b),
                         Body::Empty => return Ok(
                             Response::builder()
                                 .status(400)
@@ -28,9 +59,62 @@ async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
                     };
 
                     info!("update_sched {:?}", request);
-                    update_schedule(principal, &request?).await?;
+                    let request = match request {
+                        Ok(request) => request,
+                        Err(RequestError::Malformed(e)) => return Ok(
+                            Response::builder()
+                                .status(400)
+                                .header("content-type", "text/plain")
+                                .body(format!("The request body isn't valid JSON: {}", e).into())
+                                .map_err(Box::new)?
+                        ),
+                        Err(RequestError::Invalid(e)) => return Ok(
+                            Response::builder()
+                                .status(422)
+                                .header("content-type", "application/json")
+                                .body(serde_json::to_string(&e)?.into())
+                                .map_err(Box::new)?
+                        )
+                    };
+                    if let Err(e) = request.validate(&Limits::from_env()?) {
+                        return Ok(
+                            Response::builder()
+                                .status(422)
+                                .header("content-type", "application/json")
+                                .body(serde_json::to_string(&e)?.into())
+                                .map_err(Box::new)?
+                        )
+                    }
+                    let if_match = match event.headers().get("if-match").map(|h| h.to_str()) {
+                        Some(Ok(if_match)) => Some(if_match),
+                        Some(Err(_)) => return Ok(
+                            Response::builder()
+                                .status(400)
+                                .header("content-type", "text/plain")
+                                .body("If-Match must be ASCII.".into())
+                                .map_err(Box::new)?
+                        ),
+                        None => None
+                    };
+                    let version = match update_schedule(principal, &request, if_match, push_scheduler).await {
+                        Ok(version) => version,
+                        // Without If-Match a lost race is a conflict; with
+                        // it, the client's version was stale, unless parts
+                        // of the update were applied before the race.
+                        Err(e) => match e.downcast_ref::<ScheduleConflict>() {
+                            Some(conflict) => return Ok(
+                                Response::builder()
+                                    .status(if if_match.is_some() && conflict.applied == 0 { 412 } else { 409 })
+                                    .header("content-type", "text/plain")
+                                    .body(conflict.to_string().into())
+                                    .map_err(Box::new)?
+                            ),
+                            None => return Err(e)
+                        }
+                    };
                     Response::builder()
                         .status(204)
+                        .header("etag", etag(version))
                         .body(Body::Empty)
                         .map_err(Box::new)?
                 },
@@ -66,5 +150,7 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
-    run(service_fn(function_handler)).await
+    update_sched::check_schema().await?;
+    let push_scheduler = update_sched::push_scheduler().await?;
+    run(service_fn(|event| function_handler(event, push_scheduler.as_deref()))).await
 }
@@ -0,0 +1,27 @@
+use update_sched::{update_schedule, UpdateScheduleRequest};
+use selektor_common::push_scheduler::LocalScheduler;
+use std::time::SystemTime;
+
+#[test]
+fn test_update_schedule() {
+    let push_scheduler = LocalScheduler::default();
+    // Fired just now, so that the schedule changes on every run.
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let request: UpdateScheduleRequest = serde_json::from_str(&format!(r#"{{"entries": [
+        {{"id": "a", "last_fire": {}, "fire_interval": 3600}}
+    ]}}"#, now)).unwrap();
+    let principal = "test".to_string();
+    let future = update_schedule(&principal, &request, None, Some(&push_scheduler));
+    let res = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future);
+    println!("handler returned {:#?}", res);
+    // Each schedule gets a push schedule that fires it.
+    if res.is_ok() {
+        let schedules = push_scheduler.schedules();
+        assert_eq!(schedules.len(), 1);
+        assert_eq!(schedules[0].row_id, "test#a");
+    }
+}